@@ -0,0 +1,278 @@
+use crate::{
+    config::CsrfConfig,
+    event::was_checked,
+    fairing::{
+        describe_route, is_unsafe_method, resolve_routes, CsrfFairingCoverage, CSRF_FAILURE_PATH,
+    },
+};
+
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    http::{Method, StatusClass},
+    Build, Request, Response, Rocket,
+};
+
+/// What [`CsrfAudit`] does when it finds unprotected routes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrfAuditMode {
+    /// Log every unprotected route, but launch anyway.
+    Warn,
+    /// Log every unprotected route, then abort launch. Use this in CI.
+    FailLaunch,
+}
+
+/// How a route audited by [`CsrfAudit`] is protected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RouteCsrfProtection {
+    /// Checked by an attached [`crate::CsrfFairing`].
+    Fairing,
+    /// Declared as using a CSRF guard, see [`CsrfAudit::protected`].
+    Declared,
+    /// Exempted from an attached [`crate::CsrfFairing`], and not declared as protected.
+    Exempt,
+    /// Exempted from an attached [`crate::CsrfFairing`], but requests for another route
+    /// which is checked could match it too, so the fairing refuses every exemption.
+    AmbiguousExemption,
+    /// Not protected at all.
+    Missing,
+}
+
+/// A route with an unsafe method, as seen by [`CsrfAudit`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditedRoute {
+    /// The route's name, usually the name of the handler function.
+    pub name: Option<String>,
+    /// The route's method.
+    pub method: Method,
+    /// The route's URI, including the mount point.
+    pub uri: String,
+    /// How the route is protected.
+    pub protection: RouteCsrfProtection,
+}
+
+/// The result of a [`CsrfAudit`], added to managed state.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CsrfAuditReport {
+    /// Every route with an unsafe method, in mount order.
+    pub routes: Vec<AuditedRoute>,
+    /// Names declared with [`CsrfAudit::protected`] or `protected_routes` which no route
+    /// with an unsafe method has.
+    pub unknown_protected: Vec<String>,
+}
+
+impl CsrfAuditReport {
+    /// The routes with no CSRF protection.
+    pub fn unprotected(&self) -> impl Iterator<Item = &AuditedRoute> {
+        self.routes
+            .iter()
+            .filter(|route| route.protection == RouteCsrfProtection::Missing)
+    }
+
+    /// The number of problems found: unprotected routes, ambiguous exemptions and
+    /// unknown declared routes.
+    pub fn problems(&self) -> usize {
+        let ambiguous = self
+            .routes
+            .iter()
+            .filter(|route| route.protection == RouteCsrfProtection::AmbiguousExemption)
+            .count();
+        self.unprotected().count() + ambiguous + self.unknown_protected.len()
+    }
+}
+
+/// A fairing which lists every POST, PUT, PATCH and DELETE route at launch and reports
+/// the ones without CSRF protection.
+///
+/// Rocket does not expose the request and data guards of a route once it has been mounted,
+/// so the audit cannot look for [`crate::CsrfProtectedForm`], [`crate::CsrfProtectedFormWithGuard`],
+/// [`crate::CheckCsrfProtectionHeader`] or [`crate::CsrfCheckProof`] in route signatures.
+/// Instead a route counts as protected if
+///
+/// 1. it is covered by an attached [`crate::CsrfFairing`] and not exempt from it, or
+/// 2. it is declared as using one of those guards, by name, with [`CsrfAudit::protected`]
+///    or the `protected_routes` key of [`crate::CsrfConfig`].
+///
+/// Exemptions are resolved exactly like the fairing resolves them, so exempt routes which
+/// overlap with checked ones are reported as [`RouteCsrfProtection::AmbiguousExemption`].
+/// Declared names which match no route are reported too. Declarations cannot be checked at
+/// launch, so the audit also logs an error whenever a declared route successfully handles
+/// a request on which no CSRF check ran.
+///
+/// The audit runs at ignite so that [`CsrfAuditMode::FailLaunch`] can stop the server from
+/// starting. It only sees routes mounted before it runs, so attach it last.
+/// The full report is added to managed state as a [`CsrfAuditReport`].
+#[derive(Clone, Debug)]
+pub struct CsrfAudit {
+    mode: CsrfAuditMode,
+    protected: Vec<String>,
+}
+
+impl CsrfAudit {
+    /// Creates an audit which logs unprotected routes.
+    pub const fn warn() -> Self {
+        Self::new(CsrfAuditMode::Warn)
+    }
+
+    /// Creates an audit which aborts launch if there are any unprotected routes.
+    pub const fn fail_launch() -> Self {
+        Self::new(CsrfAuditMode::FailLaunch)
+    }
+
+    /// Creates an audit with the given mode.
+    pub const fn new(mode: CsrfAuditMode) -> Self {
+        Self {
+            mode,
+            protected: Vec::new(),
+        }
+    }
+
+    /// Declares that the route(s) with the given name use a CSRF guard.
+    #[must_use]
+    pub fn protected(mut self, route_name: impl Into<String>) -> Self {
+        self.protected.push(route_name.into());
+        self
+    }
+
+    /// Whether the route with the given name is declared as using a CSRF guard.
+    fn is_declared(&self, config: &CsrfConfig, name: &str) -> bool {
+        self.protected
+            .iter()
+            .chain(&config.protected_routes)
+            .any(|declared| declared == name)
+    }
+
+    fn report(&self, rocket: &Rocket<Build>) -> CsrfAuditReport {
+        let config = rocket.state::<CsrfConfig>().cloned().unwrap_or_default();
+        let coverage = rocket.state::<CsrfFairingCoverage>();
+        let all_routes: Vec<_> = rocket.routes().collect();
+        let exempt = coverage.map(|coverage| {
+            resolve_routes(
+                &all_routes,
+                coverage.exempt.iter().chain(&config.exempt_routes),
+            )
+        });
+        let routes: Vec<_> = all_routes
+            .iter()
+            .filter(|route| is_unsafe_method(route.method))
+            .filter(|route| route.uri.path() != CSRF_FAILURE_PATH)
+            .collect();
+        let unknown_protected = self
+            .protected
+            .iter()
+            .chain(&config.protected_routes)
+            .filter(|declared| {
+                !routes
+                    .iter()
+                    .any(|route| route.name.as_deref() == Some(declared.as_str()))
+            })
+            .cloned()
+            .collect();
+        let routes = routes
+            .into_iter()
+            .map(|route| {
+                let name = route.name.as_deref();
+                let is_route = |other: &&rocket::Route| std::ptr::eq(*other, *route);
+                let declared = name.is_some_and(|name| self.is_declared(&config, name));
+                let protection = match &exempt {
+                    _ if declared => RouteCsrfProtection::Declared,
+                    Some(exempt) if exempt.overlaps.iter().any(|(exempt, _)| is_route(exempt)) => {
+                        RouteCsrfProtection::AmbiguousExemption
+                    }
+                    Some(exempt) if exempt.routes.iter().any(is_route) => {
+                        RouteCsrfProtection::Exempt
+                    }
+                    Some(_) => RouteCsrfProtection::Fairing,
+                    None => RouteCsrfProtection::Missing,
+                };
+                AuditedRoute {
+                    name: name.map(str::to_owned),
+                    method: route.method,
+                    uri: route.uri.to_string(),
+                    protection,
+                }
+            })
+            .collect();
+        if let Some(exempt) = &exempt {
+            for (route, other) in &exempt.overlaps {
+                log::error!(
+                    "CSRF exempt route {} overlaps with checked route {}",
+                    describe_route(route),
+                    describe_route(other)
+                );
+            }
+        }
+        CsrfAuditReport {
+            routes,
+            unknown_protected,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for CsrfAudit {
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF audit",
+            kind: Kind::Ignite | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let report = self.report(&rocket);
+        for route in &report.routes {
+            let name = route.name.as_deref().unwrap_or("<unnamed>");
+            match route.protection {
+                RouteCsrfProtection::Missing => log::warn!(
+                    "no CSRF protection for route `{name}`: {} {}",
+                    route.method,
+                    route.uri
+                ),
+                RouteCsrfProtection::AmbiguousExemption => log::warn!(
+                    "ambiguous CSRF exemption for route `{name}`: {} {}",
+                    route.method,
+                    route.uri
+                ),
+                protection => log::info!(
+                    "CSRF protection for route `{name}` ({protection:?}): {} {}",
+                    route.method,
+                    route.uri
+                ),
+            }
+        }
+        for name in &report.unknown_protected {
+            log::warn!("route `{name}` is declared as CSRF protected, but there is no such route");
+        }
+        let problems = report.problems();
+        if problems > 0 && self.mode == CsrfAuditMode::FailLaunch {
+            log::error!("{problems} CSRF protection problem(s) found, aborting launch");
+            return Err(rocket);
+        }
+        Ok(rocket.manage(report))
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if !is_unsafe_method(request.method())
+            || !matches!(
+                response.status().class(),
+                StatusClass::Success | StatusClass::Redirection
+            )
+            || was_checked(request)
+        {
+            return;
+        }
+        let Some(route) = request.route() else {
+            return;
+        };
+        let config = CsrfConfig::from_request(request);
+        if route
+            .name
+            .as_deref()
+            .is_some_and(|name| self.is_declared(config, name))
+        {
+            log::error!(
+                "route {} is declared as CSRF protected, but handled a request without any CSRF check",
+                describe_route(route)
+            );
+        }
+    }
+}
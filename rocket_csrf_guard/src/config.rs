@@ -0,0 +1,256 @@
+use crate::cookie::{
+    DOUBLE_SUBMIT_CSRF_TOKEN_COOKIE_NAME, DOUBLE_SUBMIT_CSRF_TOKEN_EXPIRY_SECONDS,
+    DOUBLE_SUBMIT_CSRF_TOKEN_NONE_EXPIRY_SECONDS,
+};
+use crate::event::{register_listener, CsrfMetrics};
+use crate::extract::PEEK_BYTES;
+use crate::header::CSRF_HEADER_NAME;
+use crate::origin::{AllowedOrigin, MissingOriginPolicy};
+use crate::rotation::CSRF_TOKEN_ROTATION_GRACE_SECONDS;
+use crate::util::now_unix_seconds;
+use crate::xsrf::XSRF_COOKIE_NAME;
+
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    request::{FromRequest, Outcome},
+    serde::Deserialize,
+    Request,
+};
+
+/// Default length, in random bytes, of generated tokens.
+pub const CSRF_TOKEN_LENGTH: usize = 16;
+
+/// The shortest [`CsrfConfig::token_length`] accepted, so tokens cannot be guessed.
+pub(crate) const MIN_CSRF_TOKEN_LENGTH: usize = 16;
+
+/// Default name of the form field holding the csrf token, matching [`crate::with_csrf_token`].
+pub const CSRF_FIELD_NAME: &str = "csrf_token";
+
+/// What happens to a double submit cookie once it has been checked,
+/// see [`crate::DoubleSubmitCookieCsrfToken`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum DoubleSubmitCookiePolicy {
+    /// Remove the cookie, so every token can only be used once. Submitting a form then
+    /// invalidates the forms open in all other tabs.
+    ///
+    /// The cookie is removed by the response, so this relies on the browser: requests sent
+    /// with the same cookie before the first response arrives (such as concurrent XHRs) all
+    /// pass, as would a replayed request. Use [`crate::OneTimeCsrfToken`] if tokens must
+    /// never be accepted twice.
+    #[default]
+    Consume,
+    /// Keep the cookie until it expires, so every open form keeps working.
+    Keep,
+    /// Replace the cookie with a fresh token, which is sent back to the client by
+    /// [`crate::DoubleSubmitCookieCsrfToken::reissue_fairing`]. Suits single page apps which
+    /// pick up the new token from every response.
+    Rotate,
+}
+
+/// The key of the section in Rocket's configuration that [`CsrfConfig`] is read from.
+const CSRF_CONFIG_KEY: &str = "csrf";
+
+/// Runtime configuration for the guards in this crate.
+///
+/// Read from the `csrf` section of Rocket's configuration by [`CsrfConfig::fairing`],
+/// e.g. in `Rocket.toml`:
+///
+/// ```toml
+/// [default.csrf]
+/// cookie_name = "__Host-csrf-token"
+/// header_name = "X-CSRF-Token"
+/// field_name = "csrf_token"
+/// token_length = 16
+/// expiry_seconds = 600
+/// none_expiry_seconds = 20
+/// double_submit_policy = "consume"
+/// xsrf_cookie_name = "XSRF-TOKEN"
+/// rotation_grace_seconds = 30
+/// exempt_routes = []
+/// protected_routes = []
+/// allowed_origins = []
+/// missing_origin = "check_referer"
+/// report_only = false
+/// report_only_routes = []
+/// ```
+///
+/// Every field is optional. If the section (or the fairing) is missing, the defaults above apply.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct CsrfConfig {
+    /// Name of the double submit cookie.
+    pub cookie_name: String,
+    /// Name of the header checked by [`crate::CheckCsrfProtectionHeader`].
+    pub header_name: String,
+    /// Name of the form field or JSON key that [`crate::CsrfFairing`] reads tokens from.
+    pub field_name: String,
+    /// Number of random bytes in a double submit token. Must be at least 16, and at most
+    /// whatever still lets a masked token fit in the first 512 bytes of a body, which is where
+    /// [`crate::CsrfFairing`] looks for it: 126 with the default field name and expiries.
+    pub token_length: usize,
+    /// Expiry of double submit cookies using [`rocket::http::SameSite::Strict`] or
+    /// [`rocket::http::SameSite::Lax`], in seconds. Must be positive.
+    pub expiry_seconds: i64,
+    /// Expiry of double submit cookies using [`rocket::http::SameSite::None`], in seconds.
+    /// Must be positive.
+    pub none_expiry_seconds: i64,
+    /// What happens to double submit cookies once they have been checked.
+    pub double_submit_policy: DoubleSubmitCookiePolicy,
+    /// Name of the script readable cookie set by [`crate::SetXsrfCookieCsrfToken`].
+    pub xsrf_cookie_name: String,
+    /// How long a token replaced by [`crate::RotatingCsrfToken::rotate`] keeps working, in seconds.
+    /// Must not be negative.
+    pub rotation_grace_seconds: i64,
+    /// Names of routes which [`crate::CsrfFairing`] does not check.
+    pub exempt_routes: Vec<String>,
+    /// Names of routes which [`crate::CsrfAudit`] treats as using a CSRF guard.
+    pub protected_routes: Vec<String>,
+    /// Origins which [`crate::CheckSameOrigin`] accepts, such as `https://example.com` or
+    /// `https://*.example.com`. If empty, the origin must match the `Host` header.
+    pub allowed_origins: Vec<String>,
+    /// What [`crate::CheckSameOrigin`] does with requests which have no `Origin` header.
+    pub missing_origin: MissingOriginPolicy,
+    /// Let requests which fail CSRF checks through, only logging and counting them (see
+    /// [`crate::CsrfViolation`]), like the report-only mode of a Content Security Policy.
+    /// Use this to try out CSRF protection on an existing app before enforcing it.
+    /// The [`crate::CsrfCheckProof`]s handed out for those requests are marked with
+    /// [`crate::CsrfCheckProof::report_only`], so service layers can still refuse them.
+    pub report_only: bool,
+    /// Names of routes whose CSRF checks are in report-only mode, as if `report_only` were
+    /// set for just those routes.
+    pub report_only_routes: Vec<String>,
+}
+
+/// Errors when validating a [`CsrfConfig`].
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum CsrfConfigError {
+    #[error("invalid origin `{0}` in `allowed_origins`")]
+    InvalidOrigin(String),
+    #[error("`token_length` must be at least {MIN_CSRF_TOKEN_LENGTH}, got {0}")]
+    TokenTooShort(usize),
+    #[error("`token_length` must be at most {1} for tokens to fit in the first {PEEK_BYTES} bytes of a body, got {0}")]
+    TokenTooLong(usize, usize),
+    #[error("`{0}` must be positive, got {1}")]
+    NotPositive(&'static str, i64),
+    #[error("`{0}` must not be negative, got {1}")]
+    Negative(&'static str, i64),
+}
+
+impl Default for CsrfConfig {
+    fn default() -> Self {
+        Self {
+            cookie_name: DOUBLE_SUBMIT_CSRF_TOKEN_COOKIE_NAME.to_owned(),
+            header_name: CSRF_HEADER_NAME.to_owned(),
+            field_name: CSRF_FIELD_NAME.to_owned(),
+            token_length: CSRF_TOKEN_LENGTH,
+            expiry_seconds: DOUBLE_SUBMIT_CSRF_TOKEN_EXPIRY_SECONDS,
+            none_expiry_seconds: DOUBLE_SUBMIT_CSRF_TOKEN_NONE_EXPIRY_SECONDS,
+            double_submit_policy: DoubleSubmitCookiePolicy::default(),
+            xsrf_cookie_name: XSRF_COOKIE_NAME.to_owned(),
+            rotation_grace_seconds: CSRF_TOKEN_ROTATION_GRACE_SECONDS,
+            exempt_routes: Vec::new(),
+            protected_routes: Vec::new(),
+            allowed_origins: Vec::new(),
+            missing_origin: MissingOriginPolicy::default(),
+            report_only: false,
+            report_only_routes: Vec::new(),
+        }
+    }
+}
+
+impl CsrfConfig {
+    /// A fairing which reads the configuration and adds it, along with
+    /// [`CsrfMetrics`], to managed state.
+    /// Launch fails if the `csrf` section is present but invalid.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::try_on_ignite("CSRF configuration", |rocket| async move {
+            let metrics = CsrfMetrics::default();
+            let rocket = register_listener(rocket.manage(metrics.clone()), metrics);
+            let figment = rocket.figment();
+            if figment.find_value(CSRF_CONFIG_KEY).is_err() {
+                return Ok(rocket.manage(Self::default()));
+            }
+            match figment.extract_inner::<Self>(CSRF_CONFIG_KEY) {
+                Ok(config) => match config.validate() {
+                    Ok(()) => Ok(rocket.manage(config)),
+                    Err(e) => {
+                        log::error!("invalid `{CSRF_CONFIG_KEY}` configuration: {e}");
+                        Err(rocket)
+                    }
+                },
+                Err(e) => {
+                    log::error!("invalid `{CSRF_CONFIG_KEY}` configuration: {e}");
+                    Err(rocket)
+                }
+            }
+        })
+    }
+
+    /// Checks the values which deserialize fine but would weaken the checks.
+    fn validate(&self) -> Result<(), CsrfConfigError> {
+        if let Some(origin) = self
+            .allowed_origins
+            .iter()
+            .find(|origin| AllowedOrigin::parse(origin).is_none())
+        {
+            return Err(CsrfConfigError::InvalidOrigin(origin.clone()));
+        }
+        if self.token_length < MIN_CSRF_TOKEN_LENGTH {
+            return Err(CsrfConfigError::TokenTooShort(self.token_length));
+        }
+        if self.token_length > self.max_token_length() {
+            return Err(CsrfConfigError::TokenTooLong(
+                self.token_length,
+                self.max_token_length(),
+            ));
+        }
+        for (name, value) in [
+            ("expiry_seconds", self.expiry_seconds),
+            ("none_expiry_seconds", self.none_expiry_seconds),
+        ] {
+            if value <= 0 {
+                return Err(CsrfConfigError::NotPositive(name, value));
+            }
+        }
+        if self.rotation_grace_seconds < 0 {
+            return Err(CsrfConfigError::Negative(
+                "rotation_grace_seconds",
+                self.rotation_grace_seconds,
+            ));
+        }
+        Ok(())
+    }
+
+    /// The longest `token_length` for which a masked double submit token, and the field
+    /// holding it, fit in the [`PEEK_BYTES`] of a urlencoded form or JSON body that are checked.
+    fn max_token_length(&self) -> usize {
+        // `{"field":"` is the most either format puts before the token.
+        let room = PEEK_BYTES.saturating_sub(self.field_name.len() + 5);
+        // Masking doubles the token, then base64 turns every 3 bytes into 4 characters.
+        let token = room * 3 / 8;
+        // The issue time, expiry and SameSite setting, with their separators, come first.
+        let max_age = self.expiry_seconds.max(self.none_expiry_seconds);
+        let prefix = now_unix_seconds().to_string().len() + max_age.to_string().len() + 4;
+        token.saturating_sub(prefix) * 3 / 4
+    }
+
+    /// The configuration for the current request, falling back to the defaults
+    /// if [`CsrfConfig::fairing`] was not attached.
+    pub(crate) fn from_request<'r>(request: &'r Request<'_>) -> &'r Self {
+        request
+            .rocket()
+            .state::<Self>()
+            .unwrap_or_else(|| request.local_cache(Self::default))
+    }
+}
+
+/// Provides the configuration to routes, e.g. for [`crate::RotatingCsrfToken::rotate`].
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for &'r CsrfConfig {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(CsrfConfig::from_request(request))
+    }
+}
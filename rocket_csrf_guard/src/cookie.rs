@@ -1,21 +1,34 @@
 use crate::{
-    util::random_id, CsrfCheckProof, CsrfTokenVerificationError, CsrfTokenVerifier,
-    WithUserProvidedCsrfToken,
+    config::{CsrfConfig, DoubleSubmitCookiePolicy},
+    event::{emit, CsrfEventKind},
+    report::CsrfViolationReason,
+    util::{is_expired, mask_token, now_unix_seconds, random_id, token_matches, unmask_token},
+    verifier::sealed::ReportOnlyCheck,
+    CsrfCheckProof, CsrfTokenVerificationError, CsrfTokenVerifier, WithUserProvidedCsrfToken,
 };
 
+use std::sync::OnceLock;
+
 use rocket::{
-    http::{Cookie, CookieJar, SameSite, Status},
+    fairing::{AdHoc, Fairing},
+    http::{Cookie, CookieJar, Header, SameSite, Status},
     request::{FromRequest, Outcome, Request},
 };
-use serde::{Serialize, Serializer};
+use serde::{ser::Error as _, Serialize, Serializer};
 
-/// Default double submit cookie name.
+/// Default double submit cookie name, see [`CsrfConfig::cookie_name`].
 pub const DOUBLE_SUBMIT_CSRF_TOKEN_COOKIE_NAME: &str = "__Host-csrf-token";
 
-/// Default double submit cookie expiry time.
+/// Default double submit cookie expiry time, see [`CsrfConfig::expiry_seconds`].
 pub const DOUBLE_SUBMIT_CSRF_TOKEN_EXPIRY_SECONDS: i64 = 600;
 
-/// Default expiry time for a double submit cookie set with [`rocket::http::SameSite::None`]
+/// Reported to [`crate::CsrfEventListener`]s when a double submit token is issued.
+const TOKEN_ISSUED: CsrfEventKind = CsrfEventKind::TokenIssued {
+    verifier: "DoubleSubmitCookieCsrfToken",
+};
+
+/// Default expiry time for a double submit cookie set with [`rocket::http::SameSite::None`],
+/// see [`CsrfConfig::none_expiry_seconds`].
 pub const DOUBLE_SUBMIT_CSRF_TOKEN_NONE_EXPIRY_SECONDS: i64 = 20;
 
 /// CSRF protection using Double Submit cookies.
@@ -23,10 +36,135 @@ pub const DOUBLE_SUBMIT_CSRF_TOKEN_NONE_EXPIRY_SECONDS: i64 = 20;
 /// Provides a verifier to check a provided CSRF token against an expected value present in
 /// a cookie which was previously set using [`SetDoubleSubmitCookieCsrfToken`]
 ///
+/// Tokens are of the form `issued_at.max_age.same_site.random`, so the server can reject
+/// stale ones rather than relying on the browser to expire the cookie. The cookie is private,
+/// so clients cannot tamper with the timestamps.
+///
+/// What happens to the cookie once it has been read depends on
+/// [`CsrfConfig::double_submit_policy`], see [`DoubleSubmitCookiePolicy`].
+///
 /// Prefer using session based CSRF protection where possible.
 #[derive(Debug)]
 pub struct DoubleSubmitCookieCsrfToken(String);
 
+/// The parsed form of a double submit token.
+struct TokenLifetime {
+    issued_at: i64,
+    max_age: i64,
+    same_site: i8,
+}
+
+impl TokenLifetime {
+    /// Parses `issued_at.max_age.same_site.random`.
+    fn parse(token: &str) -> Option<Self> {
+        let parts: Vec<_> = token.split('.').collect();
+        let [issued_at, max_age, same_site, _] = parts[..] else {
+            return None;
+        };
+        Some(Self {
+            issued_at: issued_at.parse().ok()?,
+            max_age: max_age.parse().ok()?,
+            same_site: same_site.parse().ok()?,
+        })
+    }
+
+    fn is_expired(&self) -> bool {
+        is_expired(self.issued_at, self.max_age)
+    }
+}
+
+/// Generates a new token valid for `max_age` seconds, for a cookie with the given SameSite setting.
+fn new_token(token_length: usize, max_age: i64, same_site: i8) -> Result<String, rand::Error> {
+    random_id(token_length).map(|id| format!("{}.{max_age}.{same_site}.{id}", now_unix_seconds()))
+}
+
+/// Builds the (private) cookie holding a double submit token.
+fn token_cookie(name: String, token: String, max_age: i64, same_site: i8) -> Cookie<'static> {
+    let same_site = match same_site {
+        SAME_SITE_LAX => SameSite::Lax,
+        SAME_SITE_NONE_DO_NOT_USE_UNLESS_YOU_ARE_SURE => SameSite::None,
+        _ => SameSite::Strict,
+    };
+    Cookie::build((name, token))
+        .max_age(rocket::time::Duration::seconds(max_age))
+        .same_site(same_site)
+        .secure(true)
+        .build()
+}
+
+/// Works out why a request had no double submit cookie, using the `provided` token: it
+/// records when its cookie expires, so an unexpired token without a cookie means the browser
+/// is not sending cookies at all.
+pub(crate) fn missing_cookie_reason(request: &Request<'_>, provided: &str) -> CsrfViolationReason {
+    if provided.is_empty() {
+        return CsrfViolationReason::MissingToken;
+    }
+    let lifetime = unmask_token(provided)
+        .and_then(|token| String::from_utf8(token).ok())
+        .and_then(|token| TokenLifetime::parse(&token))
+        .or_else(|| TokenLifetime::parse(provided));
+    match lifetime {
+        Some(lifetime) if lifetime.is_expired() => CsrfViolationReason::ExpiredToken,
+        Some(_) if request.headers().get_one("Cookie").is_none() => {
+            CsrfViolationReason::CookiesDisabled
+        }
+        _ => CsrfViolationReason::NoVerifier,
+    }
+}
+
+/// A token issued to replace a double submit cookie under [`DoubleSubmitCookiePolicy::Rotate`].
+/// Set at most once per request, so every guard reading the cookie sees the same rotation.
+#[derive(Default)]
+struct ReissuedCsrfToken(OnceLock<String>);
+
+impl DoubleSubmitCookieCsrfToken {
+    /// A fairing which sends the token that replaced a double submit cookie back to the
+    /// client, in the configured header (`X-CSRF-Token` by default).
+    ///
+    /// Attach this when using [`DoubleSubmitCookiePolicy::Rotate`], so that scripts can
+    /// pick up the new token from every response. The token is masked.
+    pub fn reissue_fairing() -> impl Fairing {
+        AdHoc::on_response("CSRF token reissue", |request, response| {
+            Box::pin(async move {
+                let reissued: &ReissuedCsrfToken = request.local_cache(ReissuedCsrfToken::default);
+                let masked = reissued.0.get().and_then(|token| mask_token(token).ok());
+                if let Some(masked) = masked {
+                    let header_name = CsrfConfig::from_request(request).header_name.clone();
+                    response.set_header(Header::new(header_name, masked));
+                }
+            })
+        })
+    }
+
+    /// Replaces the cookie with a fresh token with the same lifetime and SameSite setting.
+    /// The cookie jar keeps returning the original cookie, so this only rotates once per
+    /// request, however many guards read the cookie.
+    fn rotate(request: &Request<'_>, config: &CsrfConfig, cookie: Cookie<'static>) {
+        let reissued: &ReissuedCsrfToken = request.local_cache(ReissuedCsrfToken::default);
+        if reissued.0.get().is_some() {
+            return;
+        }
+        let rotated = TokenLifetime::parse(cookie.value()).and_then(|lifetime| {
+            new_token(config.token_length, lifetime.max_age, lifetime.same_site)
+                .ok()
+                .map(|token| (token, lifetime))
+        });
+        match rotated {
+            Some((token, lifetime)) => {
+                request.cookies().add_private(token_cookie(
+                    config.cookie_name.clone(),
+                    token.clone(),
+                    lifetime.max_age,
+                    lifetime.same_site,
+                ));
+                let _ = reissued.0.set(token);
+                emit(request, TOKEN_ISSUED);
+            }
+            None => request.cookies().remove(cookie),
+        }
+    }
+}
+
 /// Verifies that the received token matches the cookie.
 #[async_trait::async_trait]
 impl CsrfTokenVerifier for DoubleSubmitCookieCsrfToken {
@@ -37,27 +175,44 @@ impl CsrfTokenVerifier for DoubleSubmitCookieCsrfToken {
         &self,
         token: &(dyn WithUserProvidedCsrfToken + Send + Sync),
     ) -> Result<Self::Proof, Self::Error> {
-        if token.csrf_token() == self.0 {
-            Ok(CsrfCheckProof::PassedCsrfChecks)
-        } else {
-            Err(CsrfTokenVerificationError::CsrfTokenMismatch)
+        if !token_matches(token.csrf_token(), &self.0) {
+            return Err(CsrfTokenVerificationError::CsrfTokenMismatch);
         }
+        match TokenLifetime::parse(&self.0) {
+            Some(lifetime) if lifetime.is_expired() => Err(CsrfTokenVerificationError::Expired),
+            Some(_) => Ok(CsrfCheckProof::new::<Self>(token.csrf_mechanism())),
+            // Not a token we issued.
+            None => Err(CsrfTokenVerificationError::CsrfTokenMismatch),
+        }
+    }
+
+    fn violation_reason(error: &Self::Error) -> CsrfViolationReason {
+        error.into()
+    }
+
+    fn report_only_proof(check: ReportOnlyCheck) -> Option<Self::Proof> {
+        Some(CsrfCheckProof::excused::<Self>(check.mechanism))
     }
 }
 
-/// Extracts the cookie from the request and drops it so it doesn't get reused.
+/// Extracts the cookie from the request, and by default drops it so it doesn't get reused.
 #[async_trait::async_trait]
 impl<'r> FromRequest<'r> for DoubleSubmitCookieCsrfToken {
     type Error = std::convert::Infallible;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = CsrfConfig::from_request(request);
         let maybe_csrf_token = request
             .cookies()
-            .get_private(DOUBLE_SUBMIT_CSRF_TOKEN_COOKIE_NAME)
+            .get_private(&config.cookie_name)
             .map(|cookie| {
                 let value = cookie.value().to_owned();
-                // Drop cookie so we don't reuse it
-                request.cookies().remove(cookie);
+                match config.double_submit_policy {
+                    // Drop cookie so we don't reuse it
+                    DoubleSubmitCookiePolicy::Consume => request.cookies().remove(cookie),
+                    DoubleSubmitCookiePolicy::Keep => {}
+                    DoubleSubmitCookiePolicy::Rotate => Self::rotate(request, config, cookie),
+                }
                 value
             });
         maybe_csrf_token.map_or(Outcome::Forward(Status::BadRequest), |csrf_token| {
@@ -66,16 +221,21 @@ impl<'r> FromRequest<'r> for DoubleSubmitCookieCsrfToken {
     }
 }
 
-/// Sets a Double Submit cookie with the given expiry and SameSite setting.
+/// Sets a Double Submit cookie with the given SameSite setting.
 ///
+/// The cookie name, token length and expiry are read from [`CsrfConfig`].
 /// Use this as a request guard so it sets the cookie in the returned response.
 /// This type implements [`serde::Serialize`] so you can extract the value to display
 /// it in a form or some other location so the client can pass it along in the request.
+/// Serialized values are masked, so every render looks different even though
+/// the underlying token stays the same.
 #[derive(Debug)]
-pub struct SetDoubleSubmitCookieCsrfTokenImpl<'r, const SS: i8, const EXPIRY: i64> {
+pub struct SetDoubleSubmitCookieCsrfTokenImpl<'r, const SS: i8> {
     // Keep a reference to the cookie jar so we can create the cookie if this gets
     // serialized into a form
     cookies: &'r CookieJar<'r>,
+    cookie_name: &'r str,
+    expiry_seconds: i64,
     csrf_token: String,
 }
 
@@ -83,52 +243,77 @@ const SAME_SITE_STRICT: i8 = 0;
 const SAME_SITE_LAX: i8 = 1;
 const SAME_SITE_NONE_DO_NOT_USE_UNLESS_YOU_ARE_SURE: i8 = 2;
 
-impl<'r, const SS: i8, const EXPIRY: i64> SetDoubleSubmitCookieCsrfTokenImpl<'r, SS, EXPIRY> {
+impl<'r, const SS: i8> SetDoubleSubmitCookieCsrfTokenImpl<'r, SS> {
     /// Creates a cookie with the value of the token, and returns the value.
+    ///
+    /// The returned value is the raw token. Prefer [`Self::set_masked`] when rendering it
+    /// into a (possibly compressed) response.
     pub fn set(&self) -> &str {
-        let ss = match SS {
-            SAME_SITE_LAX => SameSite::Lax,
-            SAME_SITE_NONE_DO_NOT_USE_UNLESS_YOU_ARE_SURE => SameSite::None,
-            _ => SameSite::Strict,
-        };
-        let cookie = Cookie::build((
-            DOUBLE_SUBMIT_CSRF_TOKEN_COOKIE_NAME,
+        self.cookies.add_private(token_cookie(
+            self.cookie_name.to_owned(),
             self.csrf_token.clone(),
-        ))
-        .max_age(rocket::time::Duration::seconds(EXPIRY))
-        .same_site(ss)
-        .secure(true);
-        self.cookies.add_private(cookie);
+            self.expiry_seconds,
+            SS,
+        ));
         &self.csrf_token
     }
+
+    /// Creates a cookie with the value of the token, and returns a freshly masked copy of it.
+    pub fn set_masked(&self) -> Result<String, rand::Error> {
+        mask_token(self.set())
+    }
 }
 
-/// Sets the cookie and serializes the value into the output form.
-impl<'r, const SS: i8, const EXPIRY: i64> Serialize
-    for SetDoubleSubmitCookieCsrfTokenImpl<'r, SS, EXPIRY>
-{
+/// Sets the cookie and serializes the masked value into the output form.
+impl<'r, const SS: i8> Serialize for SetDoubleSubmitCookieCsrfTokenImpl<'r, SS> {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(self.set())
+        serializer.serialize_str(&self.set_masked().map_err(S::Error::custom)?)
     }
 }
 
 /// Creates a random token which can be set as a cookie.
+///
+/// Unless [`CsrfConfig::double_submit_policy`] is [`DoubleSubmitCookiePolicy::Consume`],
+/// an unexpired token from an existing cookie with the same settings is reused instead,
+/// so that opening a form in a new tab does not invalidate the forms in other tabs.
 #[async_trait::async_trait]
-impl<'r, const SS: i8, const EXPIRY: i64> FromRequest<'r>
-    for SetDoubleSubmitCookieCsrfTokenImpl<'r, SS, EXPIRY>
-{
+impl<'r, const SS: i8> FromRequest<'r> for SetDoubleSubmitCookieCsrfTokenImpl<'r, SS> {
     type Error = std::convert::Infallible;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let maybe_csrf_token = random_id(16);
+        let config = CsrfConfig::from_request(request);
+        let expiry_seconds = if SS == SAME_SITE_NONE_DO_NOT_USE_UNLESS_YOU_ARE_SURE {
+            config.none_expiry_seconds
+        } else {
+            config.expiry_seconds
+        };
+        let existing = request
+            .cookies()
+            .get_private(&config.cookie_name)
+            .map(|cookie| cookie.value().to_owned())
+            .filter(|token| {
+                config.double_submit_policy != DoubleSubmitCookiePolicy::Consume
+                    && TokenLifetime::parse(token).is_some_and(|lifetime| {
+                        !lifetime.is_expired()
+                            && lifetime.max_age == expiry_seconds
+                            && lifetime.same_site == SS
+                    })
+            });
+        let maybe_csrf_token = match existing {
+            Some(token) => Ok(token),
+            None => new_token(config.token_length, expiry_seconds, SS)
+                .inspect(|_| emit(request, TOKEN_ISSUED)),
+        };
         maybe_csrf_token.map_or(
             Outcome::Forward(Status::InternalServerError),
             |csrf_token| {
                 Outcome::Success(Self {
                     cookies: request.cookies(),
+                    cookie_name: &config.cookie_name,
+                    expiry_seconds,
                     csrf_token,
                 })
             },
@@ -136,23 +321,17 @@ impl<'r, const SS: i8, const EXPIRY: i64> FromRequest<'r>
     }
 }
 
-/// Default [`DoubleSubmitCookieCsrfToken`] setting, using [`rocket::http::SameSite::Strict`] and an expiry of 10 minutes.
-pub type SetDoubleSubmitCookieCsrfToken<'r> = SetDoubleSubmitCookieCsrfTokenImpl<
-    'r,
-    SAME_SITE_STRICT,
-    DOUBLE_SUBMIT_CSRF_TOKEN_EXPIRY_SECONDS,
->;
+/// Default [`DoubleSubmitCookieCsrfToken`] setting, using [`rocket::http::SameSite::Strict`] and the configured expiry (10 minutes by default).
+pub type SetDoubleSubmitCookieCsrfToken<'r> =
+    SetDoubleSubmitCookieCsrfTokenImpl<'r, SAME_SITE_STRICT>;
 
-/// Lax [`DoubleSubmitCookieCsrfToken`] setting, using [`rocket::http::SameSite::Lax`] and an expiry of 10 minutes.
+/// Lax [`DoubleSubmitCookieCsrfToken`] setting, using [`rocket::http::SameSite::Lax`] and the configured expiry (10 minutes by default).
 pub type SetLaxDoubleSubmitCookieCsrfToken<'r> =
-    SetDoubleSubmitCookieCsrfTokenImpl<'r, SAME_SITE_LAX, DOUBLE_SUBMIT_CSRF_TOKEN_EXPIRY_SECONDS>;
+    SetDoubleSubmitCookieCsrfTokenImpl<'r, SAME_SITE_LAX>;
 
-/// Insecure [`DoubleSubmitCookieCsrfToken`] setting, using [`rocket::http::SameSite::None`] and an expiry of 20 seconds.
+/// Insecure [`DoubleSubmitCookieCsrfToken`] setting, using [`rocket::http::SameSite::None`] and the configured
+/// `none_expiry_seconds` (20 seconds by default).
 /// Avoid this as much as possible.
 #[allow(non_camel_case_types)]
 pub type SetNoneDoubleSubmitCookieCsrfToken_DO_NOT_USE_UNLESS_YOU_ARE_SURE<'r> =
-    SetDoubleSubmitCookieCsrfTokenImpl<
-        'r,
-        SAME_SITE_NONE_DO_NOT_USE_UNLESS_YOU_ARE_SURE,
-        DOUBLE_SUBMIT_CSRF_TOKEN_NONE_EXPIRY_SECONDS,
-    >;
+    SetDoubleSubmitCookieCsrfTokenImpl<'r, SAME_SITE_NONE_DO_NOT_USE_UNLESS_YOU_ARE_SURE>;
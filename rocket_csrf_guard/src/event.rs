@@ -0,0 +1,323 @@
+use crate::report::CsrfViolationReason;
+
+use std::fmt::Write as _;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, PoisonError, RwLock,
+};
+
+use rocket::{fairing::AdHoc, http::Method, Build, Request, Rocket};
+
+/// How a request was checked for CSRF.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CsrfMechanism {
+    /// A token in a header, e.g. by [`crate::CheckCsrfProtectionHeader`].
+    Header,
+    /// A token in a field of the body, e.g. by [`crate::CsrfProtectedForm`].
+    FormField,
+    /// The `Origin` or `Referer` header, by [`crate::CheckSameOrigin`].
+    Origin,
+    /// The `Sec-Fetch-*` headers, by [`crate::FetchMetadataPolicy`].
+    FetchMetadata,
+}
+
+impl CsrfMechanism {
+    pub(crate) const ALL: [Self; 4] = [
+        Self::Header,
+        Self::FormField,
+        Self::Origin,
+        Self::FetchMetadata,
+    ];
+
+    /// A stable, machine readable code for the mechanism, such as `form_field`.
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::Header => "header",
+            Self::FormField => "form_field",
+            Self::Origin => "origin",
+            Self::FetchMetadata => "fetch_metadata",
+        }
+    }
+}
+
+impl std::fmt::Display for CsrfMechanism {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// What happened, see [`CsrfEvent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrfEventKind {
+    /// A new token was issued. `verifier` is the name of the verifier which checks it,
+    /// such as `SignedCsrfToken`.
+    TokenIssued { verifier: &'static str },
+    /// The request passed CSRF checks.
+    Verified { mechanism: CsrfMechanism },
+    /// The request failed CSRF checks. If `report_only` is set it was let through anyway,
+    /// see [`crate::CsrfConfig::report_only`].
+    Failed {
+        mechanism: CsrfMechanism,
+        reason: CsrfViolationReason,
+        report_only: bool,
+    },
+}
+
+/// Something which happened during CSRF checks, passed to every [`CsrfEventListener`].
+///
+/// Events never contain tokens, so they are safe to log.
+#[derive(Clone, Debug)]
+pub struct CsrfEvent<'a> {
+    /// What happened.
+    pub kind: CsrfEventKind,
+    /// The name of the route, if the request had been routed. [`crate::CsrfFairing`] runs
+    /// before routing, so its events never have one.
+    pub route: Option<&'a str>,
+    /// The method of the request.
+    pub method: Method,
+    /// The path of the request, without the query string.
+    pub path: &'a str,
+}
+
+/// Receives [`CsrfEvent`]s, e.g. to tell attacks apart from expired tokens in logs or metrics.
+///
+/// Listeners are called inline while the request is handled, so they should be quick.
+/// [`CsrfMetrics`] is always registered by [`crate::CsrfConfig::fairing`]; with the `tracing`
+/// feature, `TracingCsrfEventListener` emits events as `tracing` events.
+pub trait CsrfEventListener: Send + Sync + 'static {
+    /// Called for every event.
+    fn on_event(&self, event: &CsrfEvent<'_>);
+
+    /// A fairing which registers this listener.
+    fn fairing(self) -> AdHoc
+    where
+        Self: Sized,
+    {
+        AdHoc::on_ignite("CSRF event listener", |rocket| async move {
+            register_listener(rocket, self)
+        })
+    }
+}
+
+/// The registered listeners, in managed state.
+#[derive(Clone, Default)]
+struct CsrfEventListeners(Arc<RwLock<Vec<Box<dyn CsrfEventListener>>>>);
+
+impl CsrfEventListeners {
+    fn emit(&self, event: &CsrfEvent<'_>) {
+        let listeners = self.0.read().unwrap_or_else(PoisonError::into_inner);
+        for listener in listeners.iter() {
+            listener.on_event(event);
+        }
+    }
+}
+
+pub(crate) fn register_listener(
+    rocket: Rocket<Build>,
+    listener: impl CsrfEventListener,
+) -> Rocket<Build> {
+    let (rocket, listeners) = match rocket.state::<CsrfEventListeners>().cloned() {
+        Some(listeners) => (rocket, listeners),
+        None => {
+            let listeners = CsrfEventListeners::default();
+            (rocket.manage(listeners.clone()), listeners)
+        }
+    };
+    listeners
+        .0
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Box::new(listener));
+    rocket
+}
+
+/// Whether a CSRF check ran on a request, see [`was_checked`].
+struct CsrfChecked(bool);
+
+/// Whether any CSRF check, passed or failed, ran on the request so far.
+pub(crate) fn was_checked(request: &Request<'_>) -> bool {
+    request.local_cache(|| CsrfChecked(false)).0
+}
+
+/// Sends an event about the request to every listener.
+pub(crate) fn emit(request: &Request<'_>, kind: CsrfEventKind) {
+    if !matches!(kind, CsrfEventKind::TokenIssued { .. }) {
+        request.local_cache(|| CsrfChecked(true));
+    }
+    if let Some(listeners) = request.rocket().state::<CsrfEventListeners>() {
+        listeners.emit(&CsrfEvent {
+            kind,
+            route: request.route().and_then(|route| route.name.as_deref()),
+            method: request.method(),
+            path: request.uri().path().as_str(),
+        });
+    }
+}
+
+/// Emits events about a request after it is no longer available, for guards which issue
+/// tokens outside of [`rocket::request::FromRequest::from_request`].
+#[derive(Clone)]
+pub(crate) struct CsrfEventEmitter {
+    listeners: Option<CsrfEventListeners>,
+    route: Option<String>,
+    method: Method,
+    path: String,
+}
+
+impl CsrfEventEmitter {
+    pub(crate) fn for_request(request: &Request<'_>) -> Self {
+        Self {
+            listeners: request.rocket().state::<CsrfEventListeners>().cloned(),
+            route: request
+                .route()
+                .and_then(|route| route.name.as_deref())
+                .map(str::to_owned),
+            method: request.method(),
+            path: request.uri().path().to_string(),
+        }
+    }
+
+    pub(crate) fn emit(&self, kind: CsrfEventKind) {
+        if let Some(listeners) = &self.listeners {
+            listeners.emit(&CsrfEvent {
+                kind,
+                route: self.route.as_deref(),
+                method: self.method,
+                path: &self.path,
+            });
+        }
+    }
+}
+
+/// Counts [`CsrfEvent`]s since launch, so they can be exported as metrics.
+///
+/// Added to managed state (and registered as a listener) by [`crate::CsrfConfig::fairing`].
+/// Read the counts from a metrics endpoint with `&State<CsrfMetrics>`, or render all of them
+/// with [`CsrfMetrics::to_prometheus`].
+#[derive(Clone, Debug, Default)]
+pub struct CsrfMetrics(Arc<CsrfMetricsCounters>);
+
+#[derive(Debug, Default)]
+struct CsrfMetricsCounters {
+    tokens_issued: AtomicU64,
+    verified: [AtomicU64; CsrfMechanism::ALL.len()],
+    failures: [AtomicU64; CsrfViolationReason::ALL.len()],
+    reported: AtomicU64,
+    enforced: AtomicU64,
+}
+
+impl CsrfMetrics {
+    /// Tokens issued.
+    pub fn tokens_issued(&self) -> u64 {
+        self.0.tokens_issued.load(Ordering::Relaxed)
+    }
+
+    /// Requests which passed CSRF checks using `mechanism`.
+    pub fn verified(&self, mechanism: CsrfMechanism) -> u64 {
+        self.0.verified[mechanism as usize].load(Ordering::Relaxed)
+    }
+
+    /// Requests which failed CSRF checks for `reason`, whether or not they were let through.
+    pub fn failures(&self, reason: CsrfViolationReason) -> u64 {
+        self.0.failures[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// Failures which were let through in report-only mode.
+    pub fn reported(&self) -> u64 {
+        self.0.reported.load(Ordering::Relaxed)
+    }
+
+    /// Failures which were rejected.
+    pub fn enforced(&self) -> u64 {
+        self.0.enforced.load(Ordering::Relaxed)
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        // Writing to a String cannot fail.
+        let _ = writeln!(out, "# TYPE csrf_tokens_issued_total counter");
+        let _ = writeln!(out, "csrf_tokens_issued_total {}", self.tokens_issued());
+        let _ = writeln!(out, "# TYPE csrf_verified_total counter");
+        for mechanism in CsrfMechanism::ALL {
+            let count = self.verified(mechanism);
+            let _ = writeln!(
+                out,
+                "csrf_verified_total{{mechanism=\"{mechanism}\"}} {count}"
+            );
+        }
+        let _ = writeln!(out, "# TYPE csrf_failures_total counter");
+        for reason in CsrfViolationReason::ALL {
+            let count = self.failures(reason);
+            let _ = writeln!(out, "csrf_failures_total{{reason=\"{reason}\"}} {count}");
+        }
+        let _ = writeln!(out, "# TYPE csrf_failures_reported_total counter");
+        let _ = writeln!(out, "csrf_failures_reported_total {}", self.reported());
+        let _ = writeln!(out, "# TYPE csrf_failures_enforced_total counter");
+        let _ = writeln!(out, "csrf_failures_enforced_total {}", self.enforced());
+        out
+    }
+}
+
+impl CsrfEventListener for CsrfMetrics {
+    fn on_event(&self, event: &CsrfEvent<'_>) {
+        let counters = &self.0;
+        let counter = match event.kind {
+            CsrfEventKind::TokenIssued { .. } => &counters.tokens_issued,
+            CsrfEventKind::Verified { mechanism } => &counters.verified[mechanism as usize],
+            CsrfEventKind::Failed {
+                reason,
+                report_only,
+                ..
+            } => {
+                let mode = if report_only {
+                    &counters.reported
+                } else {
+                    &counters.enforced
+                };
+                mode.fetch_add(1, Ordering::Relaxed);
+                &counters.failures[reason as usize]
+            }
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Emits [`CsrfEvent`]s as `tracing` events with the target `rocket_csrf_guard`: failures at
+/// the `WARN` level, everything else at `DEBUG`.
+///
+/// Only available with the `tracing` feature.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingCsrfEventListener;
+
+#[cfg(feature = "tracing")]
+impl CsrfEventListener for TracingCsrfEventListener {
+    fn on_event(&self, event: &CsrfEvent<'_>) {
+        let route = event.route.unwrap_or("-");
+        let method = event.method.as_str();
+        let path = event.path;
+        match event.kind {
+            CsrfEventKind::TokenIssued { verifier } => tracing::debug!(
+                target: "rocket_csrf_guard",
+                route, method, path, verifier,
+                "CSRF token issued"
+            ),
+            CsrfEventKind::Verified { mechanism } => tracing::debug!(
+                target: "rocket_csrf_guard",
+                route, method, path, mechanism = mechanism.code(),
+                "CSRF check passed"
+            ),
+            CsrfEventKind::Failed {
+                mechanism,
+                reason,
+                report_only,
+            } => tracing::warn!(
+                target: "rocket_csrf_guard",
+                route, method, path, mechanism = mechanism.code(), reason = reason.code(),
+                report_only,
+                "CSRF check failed"
+            ),
+        }
+    }
+}
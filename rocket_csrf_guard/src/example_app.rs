@@ -10,8 +10,10 @@
 //! for routes which require a valid user session can be protected against CSRF
 //! by wrapping them with [`SessionCsrfProtectedForm`], and then using the wrapped
 //! version in the route. You can look at [`do_logout`] for an example.
+//! JSON bodies work the same way with [`SessionCsrfProtectedJson`], see [`do_rename`].
 //! You are responsible for extracting the CSRF token from the session and providing it
-//! on any forms that need it - [`show_loggedin_page`] has an example of this.
+//! on any forms that need it - [`show_loggedin_page`] has an example of this, which masks
+//! the token so it looks different on every render.
 //!
 //! For forms which need CSRF protection but do not have a valid session (e.g. for login),
 //! we support the [Double Submit Cookie](https://cheatsheetseries.owasp.org/cheatsheets/Cross-Site_Request_Forgery_Prevention_Cheat_Sheet.html#double-submit-cookie) pattern.
@@ -19,35 +21,72 @@
 //! as you can see in [`do_login`].
 //! You are responsible for generating a CSRF token and providing it both in the form
 //! as well as the cookie that's set. [`show_login_page`] has an example of this.
+//! Since the cookie expires, longer forms can use [`CsrfRecoverableForm`] instead, which
+//! lets [`do_signup`] show the form again with what the user typed and a fresh token.
+//!
+//! Cookie and header names, as well as token lengths and expiry, can be configured through
+//! the `[default.csrf]` section of `Rocket.toml` once [`CsrfConfig::fairing`] is attached.
 //!
 //! Lastly, we also support checking CSRF tokens for API requests through the use of a header.
 //! Simply use [`CheckCsrfProtectionHeader`] with your appropriate session type as done here.
 //! You can find an example of this in [`check_csrf_header`]
+//!
+//! If you would rather not store a CSRF token in every session, [`SignedCsrfToken`] issues
+//! stateless tokens bound to the session instead. [`Session`] implements [`CsrfSessionBinding`]
+//! for this, and [`issue_signed_csrf_token`] and [`check_signed_csrf_header`] show how to use it.
+//! For sensitive actions, [`OneTimeCsrfToken`] issues tokens which can only be used once,
+//! as in [`issue_one_time_csrf_token`] and [`check_one_time_csrf_header`].
+//!
+//! To stop a token leaked from one form being used against other endpoints, wrap the session
+//! in [`ActionScopedCsrfToken`], which only accepts tokens minted for the matched route.
+//! [`issue_scoped_csrf_token`] and [`check_scoped_csrf_header`] show how to use it.
+//!
+//! Whenever a session's privileges change, its token should change too. [`Session`] keeps its
+//! token in a [`RotatingCsrfToken`], which [`elevate_privileges`] rotates while letting the
+//! old token keep working for a short grace window.
+//!
+//! Single page apps which read the token from a cookie and echo it back in a header (the
+//! `XSRF-TOKEN` convention of Angular and Axios) can use [`SetXsrfCookieCsrfToken`] to set a
+//! signed, script readable cookie, and check it with [`XsrfCookieCsrfToken`], as in
+//! [`issue_xsrf_cookie`] and [`check_xsrf_header`].
+//!
+//! As defense in depth, [`CheckSameOrigin`] checks that requests come from one of your own
+//! origins using the `Origin` and `Referer` headers, as in [`check_same_origin`].
+//!
+//! Requests which fail CSRF checks are rejected with a 403. [`build_rocket`] registers
+//! [`CsrfErrorResponse::catcher`] so the response says why, as an HTML page or as
+//! `application/problem+json` depending on what the client accepts.
 
 use mini_moka::sync::Cache;
 use rand::RngCore;
 use rocket::{
     form::{Form, FromForm},
     get,
-    http::{Cookie, CookieJar, SameSite, Status},
+    http::{Cookie, CookieJar, Method, SameSite, Status},
     outcome::IntoOutcome,
     post,
     request::{FromRequest, Outcome, Request},
     response::Redirect,
-    routes, uri, Build, Rocket, State,
+    routes,
+    serde::Deserialize,
+    uri, Build, Either, Rocket, State,
 };
 use rocket_dyn_templates::{context, Template};
 use sha3::{Digest, Sha3_256};
 
 extern crate self as rocket_csrf_guard;
 use super::{
-    with_csrf_token, CheckCsrfProtectionHeader, CsrfCheckProof, CsrfProtectedForm,
-    DoubleSubmitCookieCsrfProtectedForm, SetDoubleSubmitCookieCsrfToken,
-    VerifierWithKnownExpectedToken,
+    with_csrf_token, ActionScopedCsrfToken, CheckCsrfProtectionHeader, CheckSameOrigin,
+    CsrfCheckProof, CsrfConfig, CsrfErrorResponse, CsrfProtectedForm, CsrfProtectedJson,
+    CsrfRecoverableForm, CsrfResubmittableForm, CsrfSessionBinding, CsrfSigningKey,
+    DoubleSubmitCookieCsrfProtectedForm, InMemoryNonceStore, OneTimeCsrfToken, RotatingCsrfToken,
+    SetDoubleSubmitCookieCsrfToken, SetXsrfCookieCsrfToken, SignedCsrfToken,
+    VerifierWithKnownExpectedToken, XsrfCookieCsrfToken,
 };
 
 const SESSION_COOKIE_NAME: &str = "__Host-session";
 const SESSION_HEADER_NAME: &str = "Authorization";
+const SESSION_CSRF_TOKEN_MAX_AGE_SECONDS: i64 = 24 * 60 * 60;
 
 /// Generate a random ID of the appropriate length
 fn random_id(len: usize) -> String {
@@ -71,8 +110,10 @@ pub struct Session {
     session_id_hash: String,
     /// The username
     username: String,
-    /// The csrf token to authenticate requests
-    csrf_token: String,
+    /// The csrf token to authenticate requests, rotated when privileges change
+    csrf_token: RotatingCsrfToken,
+    /// Whether the user has confirmed their identity for sensitive actions
+    elevated: bool,
 }
 
 #[async_trait::async_trait]
@@ -100,9 +141,7 @@ impl<'r> FromRequest<'r> for Session {
                                 .map(|c| hash(c.value()))
                         })
                 };
-                let Some(session_id_hash) = session_info else {
-                    return None;
-                };
+                let session_id_hash = session_info?;
                 let manager = request
                     .guard::<&State<SessionManager>>()
                     .await
@@ -120,7 +159,25 @@ impl<'r> FromRequest<'r> for Session {
 impl VerifierWithKnownExpectedToken for Session {
     type Proof = CsrfCheckProof;
     fn expected_token(&self) -> &str {
-        &self.csrf_token
+        self.csrf_token.current()
+    }
+
+    fn previous_expected_token(&self) -> Option<&str> {
+        self.csrf_token.previous()
+    }
+
+    fn issued_at(&self) -> Option<i64> {
+        Some(self.csrf_token.issued_at())
+    }
+
+    fn max_age_seconds(&self) -> Option<i64> {
+        Some(SESSION_CSRF_TOKEN_MAX_AGE_SECONDS)
+    }
+}
+
+impl CsrfSessionBinding for Session {
+    fn csrf_session_id(&self) -> &str {
+        &self.session_id_hash
     }
 }
 
@@ -139,14 +196,22 @@ impl SessionManager {
         Self { sessions }
     }
 
-    fn create_session(&self, username: String, cookies: &CookieJar<'_>) -> Session {
+    fn create_session(
+        &self,
+        username: String,
+        cookies: &CookieJar<'_>,
+        config: &CsrfConfig,
+    ) -> Session {
         let session_id = random_id(16);
-        let csrf_token = random_id(16);
+        // Every login gets a new session, and with it a new csrf token
+        let csrf_token =
+            RotatingCsrfToken::new(config.token_length).expect("Couldn't generate random number");
         let session_id_hash = hash(&session_id);
         let session = Session {
             session_id_hash: session_id_hash.clone(),
             username,
             csrf_token,
+            elevated: false,
         };
         let session_cookie = Cookie::build((SESSION_COOKIE_NAME, session_id.clone()))
             .max_age(rocket::time::Duration::days(1))
@@ -162,6 +227,19 @@ impl SessionManager {
         self.sessions.get(session_id_hash)
     }
 
+    /// Elevates the session's privileges, rotating its csrf token so that one leaked
+    /// beforehand cannot be used for the newly allowed actions.
+    fn elevate(&self, mut session: Session, config: &CsrfConfig) -> Session {
+        session.elevated = true;
+        session
+            .csrf_token
+            .rotate(config.token_length, config.rotation_grace_seconds)
+            .expect("Couldn't generate random number");
+        self.sessions
+            .insert(session.session_id_hash.clone(), session.clone());
+        session
+    }
+
     /// Require a proof that we've passed CSRF checks, to avoid logout CSRF
     /// attacks. This is a contrived example, but the functionality in this library can
     /// be used to provide safe-by-default APIs which are resistant to CSRF attacks.
@@ -175,12 +253,89 @@ impl SessionManager {
 
 type VerifyCsrfTokenViaHeaders = CheckCsrfProtectionHeader<Session>;
 type SessionCsrfProtectedForm<F> = CsrfProtectedForm<Session, F>;
+type SessionCsrfProtectedJson<T> = CsrfProtectedJson<Session, T>;
 
 #[get("/header")]
 fn check_csrf_header(_csrf_check: VerifyCsrfTokenViaHeaders) -> String {
     "You successfully passed the right CSRF token, congrats!".to_string()
 }
 
+type VerifySignedCsrfTokenViaHeaders = CheckCsrfProtectionHeader<SignedCsrfToken<Session>>;
+
+#[get("/signed")]
+fn issue_signed_csrf_token(csrf_token: SignedCsrfToken<Session>) -> Result<String, Status> {
+    csrf_token.issue().map_err(|_| Status::InternalServerError)
+}
+
+#[post("/signed")]
+fn check_signed_csrf_header(_csrf_check: VerifySignedCsrfTokenViaHeaders) -> String {
+    "You successfully passed a signed CSRF token, congrats!".to_string()
+}
+
+type VerifyOneTimeCsrfTokenViaHeaders = CheckCsrfProtectionHeader<OneTimeCsrfToken<Session>>;
+
+#[get("/one-time")]
+async fn issue_one_time_csrf_token(
+    csrf_token: OneTimeCsrfToken<Session>,
+) -> Result<String, Status> {
+    csrf_token
+        .issue()
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[post("/one-time")]
+fn check_one_time_csrf_header(_csrf_check: VerifyOneTimeCsrfTokenViaHeaders) -> String {
+    "You successfully passed a one-time CSRF token, congrats!".to_string()
+}
+
+type VerifyScopedCsrfTokenViaHeaders = CheckCsrfProtectionHeader<ActionScopedCsrfToken<Session>>;
+
+#[get("/scoped")]
+fn issue_scoped_csrf_token(session: Session) -> Result<String, Status> {
+    session
+        .masked_action_scoped_token(Method::Post, "/scoped")
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[post("/scoped")]
+fn check_scoped_csrf_header(_csrf_check: VerifyScopedCsrfTokenViaHeaders) -> String {
+    "You successfully passed a CSRF token for this action, congrats!".to_string()
+}
+
+type VerifyXsrfCookieViaHeaders = CheckCsrfProtectionHeader<XsrfCookieCsrfToken>;
+
+#[get("/xsrf")]
+fn issue_xsrf_cookie(csrf_token: SetXsrfCookieCsrfToken) -> &'static str {
+    csrf_token.set();
+    "Your scripts can now read the XSRF-TOKEN cookie"
+}
+
+#[post("/xsrf")]
+fn check_xsrf_header(_csrf_check: VerifyXsrfCookieViaHeaders) -> String {
+    "You successfully echoed the XSRF-TOKEN cookie, congrats!".to_string()
+}
+
+#[post("/elevate")]
+fn elevate_privileges(
+    session: Session,
+    _csrf_check: VerifyCsrfTokenViaHeaders,
+    manager: &State<SessionManager>,
+    config: &CsrfConfig,
+) -> Result<String, Status> {
+    // In a real application, we'd ask for the password again or something.
+    let session = manager.elevate(session, config);
+    session
+        .csrf_token
+        .masked()
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[post("/origin")]
+fn check_same_origin(_origin_check: CheckSameOrigin) -> String {
+    "Your request came from the right origin, congrats!".to_string()
+}
+
 #[with_csrf_token]
 #[derive(Debug, FromForm)]
 struct LoginForm<'r> {
@@ -206,22 +361,72 @@ fn do_login(
     form: DoubleSubmitCookieCsrfProtectedForm<Form<LoginForm>>,
     manager: &State<SessionManager>,
     cookies: &CookieJar<'_>,
+    config: &CsrfConfig,
 ) -> Redirect {
     // In a real application, we'd check for passwords or something.
-    let _ = manager.create_session(form.name.clone(), cookies);
+    let _ = manager.create_session(form.name.clone(), cookies, config);
     Redirect::to(uri!(show_loggedin_page))
 }
 
+/// A form for signing up. If its double submit cookie expires before it is submitted, it is
+/// shown again with the name the user typed.
+#[with_csrf_token]
+#[derive(Debug, FromForm)]
+struct SignupForm {
+    name: String,
+    password: String,
+}
+
+impl CsrfResubmittableForm for SignupForm {
+    fn clear_secret_fields(&mut self) {
+        self.password.clear();
+    }
+}
+
+#[get("/signup")]
+fn show_signup_page(csrf_token: SetDoubleSubmitCookieCsrfToken) -> Template {
+    Template::render(
+        "signup",
+        context! {
+            csrf_token,
+            name: "",
+            message: ""
+        },
+    )
+}
+
+#[post("/signup", data = "<form>")]
+fn do_signup(form: CsrfRecoverableForm<Form<SignupForm>>) -> Either<String, (Status, Template)> {
+    match form {
+        // In a real application, we'd create the account here.
+        CsrfRecoverableForm::Verified(form) => Either::Left(format!("Welcome, {}!", form.name)),
+        CsrfRecoverableForm::Resubmit(form) => Either::Right((
+            Status::Forbidden,
+            Template::render(
+                "signup",
+                context! {
+                    csrf_token: form.csrf_token(),
+                    name: &form.name,
+                    message: form.message()
+                },
+            ),
+        )),
+    }
+}
+
 #[get("/", rank = 1)]
 fn show_loggedin_page(cookies: &CookieJar<'_>, session: Session) -> Template {
     let session_id = cookies
         .get_private(SESSION_COOKIE_NAME)
         .map(|c| c.value().to_string())
         .expect("have session id");
+    let csrf_token = session
+        .masked_expected_token()
+        .expect("Couldn't generate random number");
     Template::render(
         "loggedin",
         context! {
-            csrf_token: session.csrf_token,
+            csrf_token,
             name: session.username,
             session_id
         },
@@ -241,18 +446,51 @@ fn do_logout(
     Redirect::to(uri!(show_login_page))
 }
 
+#[with_csrf_token]
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RenameRequest {
+    name: String,
+}
+
+#[post("/rename", format = "json", data = "<request>")]
+fn do_rename(session: Session, request: SessionCsrfProtectedJson<RenameRequest>) -> String {
+    let request = request.into_innermost();
+    format!(
+        "{} would now be known as {}",
+        session.username, request.name
+    )
+}
+
 pub fn build_rocket() -> Rocket<Build> {
     rocket::build()
         .mount(
             "/",
             routes![
                 check_csrf_header,
+                issue_signed_csrf_token,
+                check_signed_csrf_header,
+                issue_one_time_csrf_token,
+                check_one_time_csrf_header,
+                issue_scoped_csrf_token,
+                check_scoped_csrf_header,
+                elevate_privileges,
+                issue_xsrf_cookie,
+                check_xsrf_header,
+                check_same_origin,
                 show_login_page,
                 show_loggedin_page,
                 do_login,
-                do_logout
+                do_logout,
+                do_rename,
+                show_signup_page,
+                do_signup
             ],
         )
         .manage(SessionManager::new())
+        .manage(InMemoryNonceStore::new())
+        .register("/", vec![CsrfErrorResponse::catcher()])
+        .attach(CsrfConfig::fairing())
+        .attach(CsrfSigningKey::fairing())
         .attach(Template::fairing())
 }
@@ -0,0 +1,181 @@
+use crate::{config::CsrfConfig, event::CsrfMechanism, token::WithUserProvidedCsrfToken};
+
+use rocket::{data::Data, http::RawStr, Request};
+
+/// How much of the body can be inspected without consuming it, see [`Data::peek`].
+pub(crate) const PEEK_BYTES: usize = 512;
+
+/// A csrf token found somewhere in the request, before any route ran.
+pub(crate) struct CsrfTokenSourcedFromRequest {
+    token: String,
+    mechanism: CsrfMechanism,
+}
+
+impl WithUserProvidedCsrfToken for CsrfTokenSourcedFromRequest {
+    fn csrf_token(&self) -> &str {
+        &self.token
+    }
+
+    fn csrf_mechanism(&self) -> CsrfMechanism {
+        self.mechanism
+    }
+}
+
+/// Finds the csrf token in the configured header, or failing that in the body.
+///
+/// Only the first [`PEEK_BYTES`] of the body are looked at, and nothing is consumed, so
+/// the body is still available to the route. Put the token first in forms and JSON bodies.
+pub(crate) async fn csrf_token_from_request(
+    request: &Request<'_>,
+    data: &mut Data<'_>,
+) -> Option<CsrfTokenSourcedFromRequest> {
+    let config = CsrfConfig::from_request(request);
+    if let Some(token) = request.headers().get_one(&config.header_name) {
+        return Some(CsrfTokenSourcedFromRequest {
+            token: token.to_owned(),
+            mechanism: CsrfMechanism::Header,
+        });
+    }
+    csrf_token_from_body(request, data).await
+}
+
+/// Finds the csrf token in the configured field of a urlencoded form, multipart form or
+/// JSON body, looking only at the first [`PEEK_BYTES`] without consuming anything.
+async fn csrf_token_from_body(
+    request: &Request<'_>,
+    data: &mut Data<'_>,
+) -> Option<CsrfTokenSourcedFromRequest> {
+    let field = &CsrfConfig::from_request(request).field_name;
+    let content_type = request.content_type()?;
+    // A multi-byte character may have been cut in half at the end, so decode lossily.
+    let prefix = String::from_utf8_lossy(data.peek(PEEK_BYTES).await).into_owned();
+    let complete = data.peek_complete();
+    let token = if content_type.is_form() {
+        token_from_form_prefix(&prefix, field, complete)
+    } else if content_type.is_form_data() {
+        token_from_multipart_prefix(&prefix, content_type.param("boundary")?, field)
+    } else if content_type.is_json() {
+        token_from_json_prefix(&prefix, field)
+    } else {
+        None
+    }?;
+    Some(CsrfTokenSourcedFromRequest {
+        token,
+        mechanism: CsrfMechanism::FormField,
+    })
+}
+
+/// Extracts `field=value` from a (possibly truncated) urlencoded form.
+fn token_from_form_prefix(prefix: &str, field: &str, complete: bool) -> Option<String> {
+    let mut pairs = prefix.split('&').peekable();
+    while let Some(pair) = pairs.next() {
+        // The last pair may have been cut short, unless we saw the whole body.
+        if pairs.peek().is_none() && !complete {
+            return None;
+        }
+        let Some((key, value)) = pair.split_once('=') else {
+            continue;
+        };
+        if RawStr::new(key).url_decode().ok()? == field {
+            return RawStr::new(value)
+                .url_decode()
+                .ok()
+                .map(|value| value.into_owned());
+        }
+    }
+    None
+}
+
+/// Extracts the value of the `field` part from a (possibly truncated) multipart form.
+///
+/// Only the first part is considered, so the token must come before any other field,
+/// and in particular before any file.
+fn token_from_multipart_prefix(prefix: &str, boundary: &str, field: &str) -> Option<String> {
+    let delimiter = format!("--{boundary}\r\n");
+    let part = prefix.strip_prefix(&delimiter)?;
+    let (headers, rest) = part.split_once("\r\n\r\n")?;
+    let is_field = headers.split("\r\n").any(|header| {
+        let Some((name, value)) = header.split_once(':') else {
+            return false;
+        };
+        let mut params = value.split(';').map(str::trim);
+        name.trim().eq_ignore_ascii_case("content-disposition")
+            && params.next() == Some("form-data")
+            && params.any(|param| param == format!("name=\"{field}\""))
+    });
+    if !is_field {
+        return None;
+    }
+    // The value ends where the next part starts, which must be within the prefix.
+    let end = rest.find(&format!("\r\n--{boundary}"))?;
+    Some(rest[..end].to_owned())
+}
+
+/// Extracts the string value of the top level `field` key from a (possibly truncated) JSON
+/// object.
+///
+/// The members before it are lexed just far enough to skip their values, so keys nested in
+/// other values, or text inside strings, are never mistaken for the field. Tokens never
+/// contain escapes, so values with escapes are rejected rather than decoded.
+fn token_from_json_prefix(prefix: &str, field: &str) -> Option<String> {
+    let mut rest = prefix.trim_start().strip_prefix('{')?;
+    loop {
+        let (key, after) = json_string(rest.trim_start())?;
+        rest = after.trim_start().strip_prefix(':')?.trim_start();
+        if key == field {
+            let (value, _) = json_string(rest)?;
+            return (!value.contains('\\')).then(|| value.to_owned());
+        }
+        rest = skip_json_value(rest)?.trim_start().strip_prefix(',')?;
+    }
+}
+
+/// Splits a JSON string off the start of `json`, returning its raw (still escaped) contents
+/// and what follows it.
+fn json_string(json: &str) -> Option<(&str, &str)> {
+    let body = json.strip_prefix('"')?;
+    let mut bytes = body.bytes().enumerate();
+    while let Some((i, byte)) = bytes.next() {
+        match byte {
+            b'\\' => {
+                bytes.next()?;
+            }
+            b'"' => return Some((&body[..i], &body[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Skips the JSON value at the start of `json`, returning what follows it.
+fn skip_json_value(json: &str) -> Option<&str> {
+    match json.as_bytes().first()? {
+        b'"' => json_string(json).map(|(_, rest)| rest),
+        b'{' | b'[' => {
+            let mut depth = 0usize;
+            let mut rest = json;
+            loop {
+                let next = rest.find(['"', '{', '[', '}', ']'])?;
+                rest = &rest[next..];
+                match rest.as_bytes()[0] {
+                    b'"' => {
+                        rest = json_string(rest)?.1;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    _ => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(&rest[1..]);
+                        }
+                    }
+                }
+                rest = &rest[1..];
+            }
+        }
+        // Numbers and literals, which must be followed by something to know they are whole.
+        _ => json
+            .find(|c: char| matches!(c, ',' | '}' | ']') || c.is_whitespace())
+            .map(|end| &json[end..]),
+    }
+}
@@ -0,0 +1,392 @@
+use crate::{
+    config::CsrfConfig,
+    event::{emit, CsrfEventKind, CsrfMechanism},
+    extract::csrf_token_from_request,
+    report::{excuse_violation, CsrfViolationReason},
+    token::WithUserProvidedCsrfToken,
+    util::set_proof_in_cache,
+    verifier::{sealed::ReportOnlyCheck, CsrfTokenVerifier},
+};
+
+use std::sync::OnceLock;
+
+use rocket::{
+    fairing::{self, Fairing, Info, Kind},
+    http::{uri::Origin, Method, Status},
+    request::{FromRequest, Outcome},
+    route::{self, Handler, Route},
+    Build, Data, Orbit, Request, Rocket,
+};
+
+/// Where requests which fail CSRF checks are sent, see [`CsrfFairing`].
+pub(crate) const CSRF_FAILURE_PATH: &str = "/__rocket_csrf_guard/forbidden";
+
+/// Requests are rerouted here ahead of any application route.
+const CSRF_FAILURE_RANK: isize = isize::MIN;
+
+/// Methods which change state, and so need CSRF protection.
+const UNSAFE_METHODS: [Method; 4] = [Method::Post, Method::Put, Method::Patch, Method::Delete];
+
+/// Whether requests using this method need to pass CSRF checks.
+pub(crate) fn is_unsafe_method(method: Method) -> bool {
+    UNSAFE_METHODS.contains(&method)
+}
+
+/// Records that a [`CsrfFairing`] is attached, for [`crate::CsrfAudit`].
+pub(crate) struct CsrfFairingCoverage {
+    /// Route names exempted with [`CsrfFairing::exempt`].
+    pub(crate) exempt: Vec<String>,
+}
+
+/// A fairing which enforces CSRF checks on every POST, PUT, PATCH and DELETE request.
+///
+/// The token is read from the configured header (see [`crate::CsrfConfig`]), or failing that
+/// from the configured field of a urlencoded form, multipart form or JSON body.
+/// Only the start of the body is inspected, so put the token first.
+/// It is then checked with the verifier `V`.
+///
+/// Requests which fail are rejected with a 403 before they reach any route. Requests which pass
+/// have the verifier's proof put in the request local cache, so a [`crate::CsrfCheckProof`]
+/// request guard succeeds without any other wiring when `V::Proof` is [`crate::CsrfCheckProof`].
+///
+/// Routes which must not be checked (webhooks, OAuth callbacks, etc.) can be exempted by name,
+/// either with [`CsrfFairing::exempt`] or the `exempt_routes` key of [`crate::CsrfConfig`].
+/// Exemptions are resolved and logged at launch, so the full list is easy to review.
+/// The fairing runs before routing, so a request is exempt if its method and path match an
+/// exempt route; the route's query and format are not taken into account. If a request for
+/// some other POST, PUT, PATCH or DELETE route could also match an exempt route, e.g. an
+/// exempt `POST /<id>` next to `POST /transfer`, nothing is exempted and the server is shut
+/// down, since the fairing could not tell the two apart.
+///
+/// Failures on routes listed in the `report_only_routes` key of [`crate::CsrfConfig`], or on
+/// any route if `report_only` is set, are logged and counted but let through, provided
+/// `V` has a [`CsrfTokenVerifier::report_only_proof`] to hand out. See
+/// [`crate::CsrfViolation`].
+pub struct CsrfFairing<V> {
+    exempt: Vec<String>,
+    exempt_routes: OnceLock<Vec<RoutePattern>>,
+    report_only_routes: OnceLock<Vec<RoutePattern>>,
+    _marker: std::marker::PhantomData<fn() -> V>,
+}
+
+impl<V> CsrfFairing<V> {
+    /// Creates a fairing which checks tokens with `V`.
+    pub const fn new() -> Self {
+        Self {
+            exempt: Vec::new(),
+            exempt_routes: OnceLock::new(),
+            report_only_routes: OnceLock::new(),
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    /// Exempts the route(s) with the given name from CSRF checks.
+    #[must_use]
+    pub fn exempt(mut self, route_name: impl Into<String>) -> Self {
+        self.exempt.push(route_name.into());
+        self
+    }
+
+    /// Whether the request is for one of the exempt routes.
+    fn is_exempt(&self, request: &Request<'_>) -> bool {
+        Self::matches_any(&self.exempt_routes, request)
+    }
+
+    /// Whether failures on the request should only be reported.
+    fn is_report_only(&self, request: &Request<'_>) -> bool {
+        Self::matches_any(&self.report_only_routes, request)
+    }
+
+    fn matches_any(routes: &OnceLock<Vec<RoutePattern>>, request: &Request<'_>) -> bool {
+        routes
+            .get()
+            .is_some_and(|routes| routes.iter().any(|route| route.matches(request)))
+    }
+}
+
+impl<V> Default for CsrfFairing<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> CsrfFairing<V>
+where
+    V: CsrfTokenVerifier + for<'r> FromRequest<'r> + Send + Sync + 'static,
+{
+    /// Runs CSRF checks on the request, returning where the token was found (the header if
+    /// it was not found at all) along with the proof or why they failed.
+    async fn check(
+        request: &Request<'_>,
+        data: &mut Data<'_>,
+    ) -> (CsrfMechanism, Result<V::Proof, CsrfViolationReason>) {
+        let Some(token) = csrf_token_from_request(request, data).await else {
+            return (
+                CsrfMechanism::Header,
+                Err(CsrfViolationReason::MissingToken),
+            );
+        };
+        let Outcome::Success(verifier) = request.guard::<V>().await else {
+            return (token.csrf_mechanism(), Err(CsrfViolationReason::NoVerifier));
+        };
+        let result = verifier
+            .verify(&token)
+            .await
+            .map_err(|e| V::violation_reason(&e));
+        (token.csrf_mechanism(), result)
+    }
+}
+
+#[rocket::async_trait]
+impl<V> Fairing for CsrfFairing<V>
+where
+    V: CsrfTokenVerifier + for<'r> FromRequest<'r> + Send + Sync + 'static,
+{
+    fn info(&self) -> Info {
+        Info {
+            name: "CSRF protection",
+            kind: Kind::Ignite | Kind::Liftoff | Kind::Request,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let routes: Vec<_> = UNSAFE_METHODS
+            .iter()
+            .map(|method| {
+                Route::ranked(
+                    CSRF_FAILURE_RANK,
+                    *method,
+                    CSRF_FAILURE_PATH,
+                    CsrfFailureHandler,
+                )
+            })
+            .collect();
+        let rocket = rocket.mount("/", routes);
+        if rocket.state::<CsrfFairingCoverage>().is_some() {
+            return Ok(rocket);
+        }
+        Ok(rocket.manage(CsrfFairingCoverage {
+            exempt: self.exempt.clone(),
+        }))
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let config = rocket.state::<CsrfConfig>().cloned().unwrap_or_default();
+        let routes: Vec<_> = rocket.routes().collect();
+        let exempt = resolve_routes(&routes, self.exempt.iter().chain(&config.exempt_routes));
+        exempt.log("disabled");
+        let report_only = resolve_routes(&routes, config.report_only_routes.iter());
+        report_only.log("only reported");
+        let exempt_routes = if exempt.overlaps.is_empty() {
+            exempt.patterns()
+        } else {
+            for (route, other) in &exempt.overlaps {
+                log::error!(
+                    "CSRF exempt route {} overlaps with checked route {}",
+                    describe_route(route),
+                    describe_route(other)
+                );
+            }
+            log::error!("CSRF exemptions are ambiguous, so none apply; shutting down");
+            rocket.shutdown().notify();
+            Vec::new()
+        };
+        // Only ever set once per launch, so these cannot fail.
+        let _ = self.exempt_routes.set(exempt_routes);
+        let _ = self.report_only_routes.set(report_only.patterns());
+        if config.report_only {
+            log::warn!("CSRF checks are only reported for all routes");
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, data: &mut Data<'_>) {
+        if !is_unsafe_method(request.method()) || self.is_exempt(request) {
+            return;
+        }
+        let proof = match Self::check(request, data).await {
+            (mechanism, Ok(proof)) => {
+                emit(request, CsrfEventKind::Verified { mechanism });
+                Some(proof)
+            }
+            (mechanism, Err(reason)) => {
+                let report_only =
+                    CsrfConfig::from_request(request).report_only || self.is_report_only(request);
+                excuse_violation(
+                    request,
+                    mechanism,
+                    reason,
+                    report_only,
+                    V::report_only_proof(ReportOnlyCheck { mechanism }),
+                )
+            }
+        };
+        match proof {
+            Some(proof) => set_proof_in_cache(request, proof),
+            None => request.set_uri(Origin::parse(CSRF_FAILURE_PATH).expect("valid path")),
+        }
+    }
+}
+
+/// Routes looked up by name, see [`resolve_routes`].
+pub(crate) struct ResolvedRoutes<'r> {
+    /// The routes with the given names.
+    pub(crate) routes: Vec<&'r Route>,
+    /// The names which no route has.
+    pub(crate) unknown: Vec<&'r str>,
+    /// Pairs of a named route and another POST, PUT, PATCH or DELETE route, which requests
+    /// could match both of. [`CsrfFairing`] cannot tell those requests apart.
+    pub(crate) overlaps: Vec<(&'r Route, &'r Route)>,
+}
+
+impl ResolvedRoutes<'_> {
+    /// Logs what happens to the CSRF checks of the routes.
+    fn log(&self, action: &str) {
+        for name in &self.unknown {
+            log::error!("CSRF checks {action} for unknown route `{name}`, ignored");
+        }
+        for route in &self.routes {
+            log::warn!("CSRF checks {action} for route {}", describe_route(route));
+        }
+    }
+
+    /// The routes, as patterns to match requests against.
+    fn patterns(&self) -> Vec<RoutePattern> {
+        self.routes
+            .iter()
+            .map(|route| RoutePattern::new(route))
+            .collect()
+    }
+}
+
+/// Looks up the routes with the given names among `routes`, which should be all the
+/// mounted routes.
+pub(crate) fn resolve_routes<'r>(
+    routes: &[&'r Route],
+    names: impl Iterator<Item = &'r String>,
+) -> ResolvedRoutes<'r> {
+    let mut named: Vec<&Route> = Vec::new();
+    let mut unknown = Vec::new();
+    for name in names {
+        let len = named.len();
+        named.extend(
+            routes
+                .iter()
+                .filter(|route| route.name.as_deref() == Some(name.as_str())),
+        );
+        if named.len() == len {
+            unknown.push(name.as_str());
+        }
+    }
+    let others: Vec<_> = routes
+        .iter()
+        .copied()
+        .filter(|route| is_unsafe_method(route.method) && route.uri.path() != CSRF_FAILURE_PATH)
+        .filter(|route| !named.iter().any(|named| std::ptr::eq(*named, *route)))
+        .collect();
+    let mut overlaps = Vec::new();
+    for route in &named {
+        let pattern = RoutePattern::new(route);
+        for other in &others {
+            if pattern.overlaps(&RoutePattern::new(other)) {
+                overlaps.push((*route, *other));
+            }
+        }
+    }
+    ResolvedRoutes {
+        routes: named,
+        unknown,
+        overlaps,
+    }
+}
+
+/// Describes a route for logs, e.g. ``"`transfer` (POST /transfer)"``.
+pub(crate) fn describe_route(route: &Route) -> String {
+    let name = route.name.as_deref().unwrap_or("<unnamed>");
+    format!("`{name}` ({} {})", route.method, route.uri)
+}
+
+/// Rejects requests which were rerouted by [`CsrfFairing`].
+#[derive(Clone)]
+struct CsrfFailureHandler;
+
+#[rocket::async_trait]
+impl Handler for CsrfFailureHandler {
+    async fn handle<'r>(&self, _request: &'r Request<'_>, _data: Data<'r>) -> route::Outcome<'r> {
+        route::Outcome::Error(Status::Forbidden)
+    }
+}
+
+/// A route which [`CsrfFairing`] matches requests against, since it runs before routing.
+struct RoutePattern {
+    method: Method,
+    segments: Vec<Segment>,
+}
+
+impl RoutePattern {
+    fn new(route: &Route) -> Self {
+        Self {
+            method: route.method,
+            segments: route
+                .uri
+                .origin
+                .path()
+                .raw_segments()
+                .map(|segment| Segment::new(segment.as_str()))
+                .collect(),
+        }
+    }
+
+    /// Matches the request path against the route's.
+    fn matches(&self, request: &Request<'_>) -> bool {
+        if request.method() != self.method {
+            return false;
+        }
+        let mut request_segments = request.uri().path().segments();
+        for segment in &self.segments {
+            match (segment, request_segments.next()) {
+                (Segment::Trailing, _) => return true,
+                (Segment::Dynamic, Some(_)) => {}
+                (Segment::Static(segment), Some(request_segment)) if request_segment == segment => {
+                }
+                _ => return false,
+            }
+        }
+        request_segments.next().is_none()
+    }
+
+    /// Whether some request could match both this route and `other`.
+    fn overlaps(&self, other: &Self) -> bool {
+        if self.method != other.method {
+            return false;
+        }
+        let mut segments = self.segments.iter();
+        let mut other_segments = other.segments.iter();
+        loop {
+            match (segments.next(), other_segments.next()) {
+                (Some(Segment::Trailing), _) | (_, Some(Segment::Trailing)) => return true,
+                (None, None) => return true,
+                (Some(Segment::Static(a)), Some(Segment::Static(b))) if a != b => return false,
+                (Some(_), Some(_)) => {}
+                (Some(_), None) | (None, Some(_)) => return false,
+            }
+        }
+    }
+}
+
+/// A segment of a route's path, following Rocket's rules for `<dynamic>` and
+/// `<trailing..>` segments.
+enum Segment {
+    Static(String),
+    Dynamic,
+    Trailing,
+}
+
+impl Segment {
+    fn new(segment: &str) -> Self {
+        match segment.strip_prefix('<').and_then(|s| s.strip_suffix('>')) {
+            Some(name) if name.ends_with("..") => Self::Trailing,
+            Some(_) => Self::Dynamic,
+            None => Self::Static(segment.to_owned()),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{
+    event::{emit, CsrfEventKind, CsrfMechanism},
+    fairing::is_unsafe_method,
+    header::CheckCsrfProtectionHeader,
+    proof::CsrfCheckProof,
+    report::{report_only_check_proof, CsrfViolationReason},
+    util::set_proof_in_cache,
+    verifier::CsrfTokenVerifier,
+};
+
+use rocket::{
+    http::{Method, Status},
+    request::{self, FromRequest, Request},
+};
+
+/// Values of `Sec-Fetch-Site` for requests which did not come from another site.
+/// `none` means the user started the request themselves, e.g. by typing in the URL.
+const TRUSTED_FETCH_SITES: [&str; 3] = ["same-origin", "same-site", "none"];
+
+/// Errors when validating a [`FetchMetadataPolicyImpl`]
+#[derive(Debug)]
+pub enum FetchMetadataPolicyError {
+    /// The browser reported that the request came from another site.
+    CrossSiteRequest,
+    /// The browser sent no fetch metadata, and the request did not pass a valid csrf token
+    /// in the header either.
+    CsrfTokenCheckFailed,
+}
+
+/// A request guard implementing a [Fetch Metadata resource isolation policy](https://web.dev/articles/fetch-metadata).
+///
+/// Browsers report where a request came from in the `Sec-Fetch-Site`, `Sec-Fetch-Mode` and
+/// `Sec-Fetch-Dest` headers. Requests from the same origin, the same site, or started by the
+/// user are let through with [`CsrfCheckProof`] in the request local cache, just like
+/// [`crate::CheckCsrfProtectionHeader`] does. Cross site requests are rejected, except for
+/// top level `GET` and `HEAD` navigations when `ALLOW_NAVIGATION` is set.
+///
+/// Older browsers do not send these headers. For those, unsafe requests fall back to
+/// checking the csrf token header with the verifier `V`, while safe requests are let through
+/// without a proof.
+///
+/// Use the type aliases instead of this type directly:
+/// [`FetchMetadataPolicy`] for endpoints which are only ever fetched by your own pages
+/// (APIs, form submissions, etc.), and [`NavigationalFetchMetadataPolicy`] for pages other
+/// sites may link to.
+#[derive(Debug)]
+pub struct FetchMetadataPolicyImpl<V, const ALLOW_NAVIGATION: bool>(std::marker::PhantomData<V>);
+
+/// Rejects all cross site requests, see [`FetchMetadataPolicyImpl`].
+pub type FetchMetadataPolicy<V> = FetchMetadataPolicyImpl<V, false>;
+
+/// Rejects cross site requests other than top level navigations, see [`FetchMetadataPolicyImpl`].
+pub type NavigationalFetchMetadataPolicy<V> = FetchMetadataPolicyImpl<V, true>;
+
+/// Whether the request is a top level navigation which other sites are allowed to make.
+fn is_cross_site_navigation(request: &Request<'_>) -> bool {
+    let headers = request.headers();
+    headers.get_one("Sec-Fetch-Mode") == Some("navigate")
+        && matches!(request.method(), Method::Get | Method::Head)
+        && !matches!(headers.get_one("Sec-Fetch-Dest"), Some("object" | "embed"))
+}
+
+#[async_trait::async_trait]
+impl<'r, V, const ALLOW_NAVIGATION: bool> FromRequest<'r>
+    for FetchMetadataPolicyImpl<V, ALLOW_NAVIGATION>
+where
+    V: CsrfTokenVerifier + FromRequest<'r> + Send + Sync,
+{
+    type Error = FetchMetadataPolicyError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(site) = request.headers().get_one("Sec-Fetch-Site") else {
+            if !is_unsafe_method(request.method()) {
+                return request::Outcome::Success(Self(std::marker::PhantomData));
+            }
+            return match request.guard::<CheckCsrfProtectionHeader<V>>().await {
+                request::Outcome::Success(_) => {
+                    request::Outcome::Success(Self(std::marker::PhantomData))
+                }
+                request::Outcome::Error((status, _)) => request::Outcome::Error((
+                    status,
+                    FetchMetadataPolicyError::CsrfTokenCheckFailed,
+                )),
+                request::Outcome::Forward(f) => request::Outcome::Forward(f),
+            };
+        };
+        if TRUSTED_FETCH_SITES.contains(&site) {
+            emit(
+                request,
+                CsrfEventKind::Verified {
+                    mechanism: CsrfMechanism::FetchMetadata,
+                },
+            );
+            let proof = CsrfCheckProof::new::<Self>(CsrfMechanism::FetchMetadata);
+            set_proof_in_cache(request, proof);
+            return request::Outcome::Success(Self(std::marker::PhantomData));
+        }
+        if ALLOW_NAVIGATION && is_cross_site_navigation(request) {
+            return request::Outcome::Success(Self(std::marker::PhantomData));
+        }
+        match report_only_check_proof::<Self>(
+            request,
+            CsrfMechanism::FetchMetadata,
+            CsrfViolationReason::CrossSiteRequest,
+        ) {
+            Some(proof) => {
+                set_proof_in_cache(request, proof);
+                request::Outcome::Success(Self(std::marker::PhantomData))
+            }
+            None => request::Outcome::Error((
+                Status::Forbidden,
+                FetchMetadataPolicyError::CrossSiteRequest,
+            )),
+        }
+    }
+}
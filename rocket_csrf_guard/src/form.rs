@@ -1,5 +1,9 @@
 use crate::{
-    token::WithUserProvidedCsrfToken, util::set_proof_in_cache, verifier::CsrfTokenVerifier,
+    event::{emit, CsrfEventKind, CsrfMechanism},
+    report::{enforce_violation, report_only_proof, route_is_report_only, CsrfViolationReason},
+    token::WithUserProvidedCsrfToken,
+    util::set_proof_in_cache,
+    verifier::CsrfTokenVerifier,
 };
 
 use std::ops::{Deref, DerefMut};
@@ -9,6 +13,7 @@ use rocket::{
     form::Form,
     http::Status,
     request::{self, FromRequest, Request},
+    serde::json::Json,
 };
 
 /// Errors when validating a [`CsrfProtectedForm`]
@@ -35,6 +40,14 @@ pub enum CsrfProtectedFormWithGuardError<T, E> {
 }
 
 /// A wrapper form which parses the initial form, dereferences to it, and ensures CSRF checks pass
+///
+/// The form is parsed before the token is checked. To reject forged requests without
+/// reading their body, use [`crate::CsrfPreverifiedForm`] instead. Requests without a
+/// verifier (e.g. without a double submit cookie) are rejected before parsing, unless the
+/// route is in report-only mode.
+///
+/// In report-only mode (see [`crate::CsrfConfig::report_only`]), forms which fail the check
+/// are accepted with the verifier's [`CsrfTokenVerifier::report_only_proof`].
 pub struct CsrfProtectedForm<V, F>
 where
     V: CsrfTokenVerifier,
@@ -48,6 +61,14 @@ impl<V, F> CsrfProtectedForm<V, F>
 where
     V: CsrfTokenVerifier,
 {
+    pub(crate) const fn new(form: F, proof: V::Proof) -> Self {
+        Self {
+            form,
+            proof,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
     #[allow(clippy::missing_const_for_fn)]
     pub fn into_inner(self) -> F {
         self.form
@@ -69,6 +90,64 @@ where
     }
 }
 
+impl<V, T> CsrfProtectedForm<V, Json<T>>
+where
+    V: CsrfTokenVerifier,
+{
+    /// Extracts the inner value, throwing away the proof.
+    pub fn into_innermost(self) -> T {
+        self.form.into_inner()
+    }
+
+    /// Extracts the inner value and proof.
+    pub fn into_parts(self) -> (V::Proof, T) {
+        (self.proof, self.form.into_inner())
+    }
+}
+
+/// Records a request which had no verifier, on a route where that is enforced. It is
+/// rejected before the body is parsed, since the token cannot be checked anyway.
+fn reject_without_verifier(request: &Request<'_>) {
+    enforce_violation(
+        request,
+        CsrfMechanism::FormField,
+        CsrfViolationReason::NoVerifier,
+    );
+}
+
+/// Checks the form's token with the verifier, if there was one. In report-only mode, failures
+/// are excused with the verifier's report-only proof.
+async fn verify_form<V, F, T>(
+    request: &Request<'_>,
+    verifier: Result<V, Status>,
+    form: &F,
+) -> Result<V::Proof, (Status, CsrfProtectedFormError<T>)>
+where
+    V: CsrfTokenVerifier,
+    F: WithUserProvidedCsrfToken + Send + Sync,
+{
+    let mechanism = form.csrf_mechanism();
+    let (status, error, reason) = match verifier {
+        Ok(verifier) => match verifier.verify(form).await {
+            Ok(proof) => {
+                emit(request, CsrfEventKind::Verified { mechanism });
+                return Ok(proof);
+            }
+            Err(e) => (
+                Status::Forbidden,
+                CsrfProtectedFormError::CsrfTokenVerificationError,
+                V::violation_reason(&e),
+            ),
+        },
+        Err(status) => (
+            status,
+            CsrfProtectedFormError::NoVerifierFound,
+            CsrfViolationReason::NoVerifier,
+        ),
+    };
+    report_only_proof::<V>(request, mechanism, reason).ok_or((status, error))
+}
+
 #[async_trait::async_trait]
 impl<'r, V, F> FromData<'r> for CsrfProtectedForm<V, F>
 where
@@ -80,10 +159,12 @@ where
 
     async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
         let verifier = match request.guard::<V>().await {
-            request::Outcome::Success(verifier) => verifier,
-            request::Outcome::Error((status, _)) => {
-                return data::Outcome::Error((status, CsrfProtectedFormError::NoVerifierFound))
+            request::Outcome::Success(verifier) => Ok(verifier),
+            request::Outcome::Error((status, _)) if !route_is_report_only(request) => {
+                reject_without_verifier(request);
+                return data::Outcome::Error((status, CsrfProtectedFormError::NoVerifierFound));
             }
+            request::Outcome::Error((status, _)) => Err(status),
             request::Outcome::Forward(status) => return data::Outcome::Forward((data, status)),
         };
         let inner = match F::from_data(request, data).await {
@@ -93,20 +174,16 @@ where
             }
             data::Outcome::Forward(f) => return data::Outcome::Forward(f),
         };
-        (verifier.verify(&inner).await).map_or(
-            data::Outcome::Error((
-                Status::Forbidden,
-                CsrfProtectedFormError::CsrfTokenVerificationError,
-            )),
-            |proof| {
-                set_proof_in_cache(request, proof.clone());
-                data::Outcome::Success(Self {
-                    form: inner,
-                    proof,
-                    _marker: std::marker::PhantomData,
-                })
-            },
-        )
+        let proof = match verify_form(request, verifier, &inner).await {
+            Ok(proof) => proof,
+            Err((status, e)) => return data::Outcome::Error((status, e)),
+        };
+        set_proof_in_cache(request, proof.clone());
+        data::Outcome::Success(Self {
+            form: inner,
+            proof,
+            _marker: std::marker::PhantomData,
+        })
     }
 }
 
@@ -160,6 +237,22 @@ where
     }
 }
 
+impl<'r, V, T, G> CsrfProtectedFormWithGuard<'r, V, Json<T>, G>
+where
+    V: CsrfTokenVerifier,
+    G: FromRequest<'r>,
+{
+    /// Extracts the inner value, guard, and proof.
+    pub fn into_parts_with_proof(self) -> (V::Proof, G, T) {
+        (self.proof, self.guard, self.form.into_inner())
+    }
+
+    /// Extracts the inner value and guard, throwing away the proof.
+    pub fn into_parts(self) -> (G, T) {
+        (self.guard, self.form.into_inner())
+    }
+}
+
 #[async_trait::async_trait]
 impl<'r, V, F, G> FromData<'r> for CsrfProtectedFormWithGuard<'r, V, F, G>
 where
@@ -174,15 +267,17 @@ where
 
     async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
         let verifier = match request.guard::<V>().await {
-            request::Outcome::Success(verifier) => verifier,
-            request::Outcome::Error((status, _)) => {
+            request::Outcome::Success(verifier) => Ok(verifier),
+            request::Outcome::Error((status, _)) if !route_is_report_only(request) => {
+                reject_without_verifier(request);
                 return data::Outcome::Error((
                     status,
                     CsrfProtectedFormWithGuardError::CsrfProtection(
                         CsrfProtectedFormError::NoVerifierFound,
                     ),
-                ))
+                ));
             }
+            request::Outcome::Error((status, _)) => Err(status),
             request::Outcome::Forward(status) => return data::Outcome::Forward((data, status)),
         };
         let form = match F::from_data(request, data).await {
@@ -197,31 +292,30 @@ where
             }
             data::Outcome::Forward(f) => return data::Outcome::Forward(f),
         };
-        match verifier.verify(&form).await {
-            Ok(proof) => {
-                set_proof_in_cache(request, proof.clone());
-                match request.guard::<G>().await {
-                    request::Outcome::Success(guard) => data::Outcome::Success(Self {
-                        form,
-                        proof,
-                        guard,
-                        _marker: std::marker::PhantomData,
-                    }),
-                    request::Outcome::Error((status, error)) => data::Outcome::Error((
-                        status,
-                        CsrfProtectedFormWithGuardError::FromRequestFailed(status, error),
-                    )),
-                    request::Outcome::Forward(_) => data::Outcome::Error((
-                        Status::InternalServerError,
-                        CsrfProtectedFormWithGuardError::FromRequestForwarded,
-                    )),
-                }
+        let proof = match verify_form(request, verifier, &form).await {
+            Ok(proof) => proof,
+            Err((status, e)) => {
+                return data::Outcome::Error((
+                    status,
+                    CsrfProtectedFormWithGuardError::CsrfProtection(e),
+                ))
             }
-            Err(_) => data::Outcome::Error((
-                Status::Forbidden,
-                CsrfProtectedFormWithGuardError::CsrfProtection(
-                    CsrfProtectedFormError::CsrfTokenVerificationError,
-                ),
+        };
+        set_proof_in_cache(request, proof.clone());
+        match request.guard::<G>().await {
+            request::Outcome::Success(guard) => data::Outcome::Success(Self {
+                form,
+                proof,
+                guard,
+                _marker: std::marker::PhantomData,
+            }),
+            request::Outcome::Error((status, error)) => data::Outcome::Error((
+                status,
+                CsrfProtectedFormWithGuardError::FromRequestFailed(status, error),
+            )),
+            request::Outcome::Forward(_) => data::Outcome::Error((
+                Status::InternalServerError,
+                CsrfProtectedFormWithGuardError::FromRequestForwarded,
             )),
         }
     }
@@ -1,5 +1,10 @@
 use crate::{
-    token::WithUserProvidedCsrfToken, util::set_proof_in_cache, verifier::CsrfTokenVerifier,
+    config::CsrfConfig,
+    event::{emit, CsrfEventKind, CsrfMechanism},
+    report::{report_only_proof, CsrfViolationReason},
+    token::WithUserProvidedCsrfToken,
+    util::set_proof_in_cache,
+    verifier::CsrfTokenVerifier,
 };
 
 use rocket::{
@@ -8,7 +13,8 @@ use rocket::{
 };
 use serde::Serialize;
 
-const CSRF_HEADER_NAME: &str = "X-CSRF-Token";
+/// Default name of the header checked for CSRF tokens, see [`CsrfConfig::header_name`].
+pub const CSRF_HEADER_NAME: &str = "X-CSRF-Token";
 
 /// Errors when validating a [`CheckCsrfProtectionHeader`]
 #[derive(Debug)]
@@ -29,9 +35,16 @@ impl<'r> WithUserProvidedCsrfToken for CsrfTokenSourcedFromHeader<'r> {
     fn csrf_token(&self) -> &str {
         self.0
     }
+
+    fn csrf_mechanism(&self) -> CsrfMechanism {
+        CsrfMechanism::Header
+    }
 }
 
 /// A wrapper which verifies that a request has passed CSRF checks via checking for the headers
+///
+/// In report-only mode (see [`CsrfConfig::report_only`]), requests which fail are let through
+/// with the verifier's [`CsrfTokenVerifier::report_only_proof`] in the request local cache.
 #[derive(Debug, Serialize)]
 pub struct CheckCsrfProtectionHeader<V>(std::marker::PhantomData<V>);
 
@@ -46,29 +59,64 @@ where
         let verifier = match request.guard::<V>().await {
             request::Outcome::Success(verifier) => verifier,
             request::Outcome::Error((status, _)) => {
-                return request::Outcome::Error((
+                return Self::fail(
+                    request,
                     status,
                     CheckCsrfProtectionHeaderError::NoVerifierFound,
-                ))
+                    CsrfViolationReason::NoVerifier,
+                )
             }
             request::Outcome::Forward(f) => return request::Outcome::Forward(f),
         };
-        let token = request.headers().get_one(CSRF_HEADER_NAME);
-        match token {
-            Some(token) => (verifier.verify(&CsrfTokenSourcedFromHeader(token)).await).map_or(
-                request::Outcome::Error((
-                    Status::Forbidden,
-                    CheckCsrfProtectionHeaderError::CsrfTokenVerificationError,
-                )),
-                |proof| {
-                    set_proof_in_cache(request, proof);
-                    request::Outcome::Success(Self(std::marker::PhantomData))
-                },
-            ),
-            None => request::Outcome::Error((
+        let token = request
+            .headers()
+            .get_one(&CsrfConfig::from_request(request).header_name);
+        let Some(token) = token else {
+            return Self::fail(
+                request,
                 Status::Forbidden,
                 CheckCsrfProtectionHeaderError::NoHeaderPresent,
-            )),
+                CsrfViolationReason::MissingToken,
+            );
+        };
+        match verifier.verify(&CsrfTokenSourcedFromHeader(token)).await {
+            Ok(proof) => {
+                emit(
+                    request,
+                    CsrfEventKind::Verified {
+                        mechanism: CsrfMechanism::Header,
+                    },
+                );
+                set_proof_in_cache(request, proof);
+                request::Outcome::Success(Self(std::marker::PhantomData))
+            }
+            Err(e) => Self::fail(
+                request,
+                Status::Forbidden,
+                CheckCsrfProtectionHeaderError::CsrfTokenVerificationError,
+                V::violation_reason(&e),
+            ),
+        }
+    }
+}
+
+impl<V> CheckCsrfProtectionHeader<V>
+where
+    V: CsrfTokenVerifier,
+{
+    /// Reports a failed check, rejecting the request unless it is in report-only mode.
+    fn fail(
+        request: &Request<'_>,
+        status: Status,
+        error: CheckCsrfProtectionHeaderError,
+        reason: CsrfViolationReason,
+    ) -> request::Outcome<Self, CheckCsrfProtectionHeaderError> {
+        match report_only_proof::<V>(request, CsrfMechanism::Header, reason) {
+            Some(proof) => {
+                set_proof_in_cache(request, proof);
+                request::Outcome::Success(Self(std::marker::PhantomData))
+            }
+            None => request::Outcome::Error((status, error)),
         }
     }
 }
@@ -0,0 +1,236 @@
+use crate::{
+    signed::CsrfSigningKey,
+    util::{constant_time_eq, is_expired, now_unix_seconds},
+    CsrfTokenVerificationError,
+};
+
+use std::sync::Arc;
+
+use rocket::{serde::Deserialize, Build, Rocket};
+
+/// The key of the configuration that [`CsrfKeyring`] is read from.
+pub(crate) const SIGNING_KEYS_CONFIG_KEY: &str = "csrf.signing_keys";
+
+/// ID of the key derived from Rocket's `secret_key`, used when no keys are configured.
+pub const DEFAULT_SIGNING_KEY_ID: &str = "default";
+
+/// What a key in a [`CsrfKeyring`] is used for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SigningKeyStatus {
+    /// Signs new tokens, and verifies them. Exactly one key must be the primary.
+    Primary,
+    /// Only verifies tokens, e.g. those signed by the previous primary key.
+    #[default]
+    Verify,
+    /// Tokens signed with this key are rejected with
+    /// [`CsrfTokenVerificationError::RetiredKey`]. That is only reported once the token is
+    /// authenticated, so keep the secret for as long as you want users to be told their token
+    /// was signed with a retired key. Once it is removed, those tokens are just invalid.
+    Retired,
+}
+
+/// A key as it appears in the configuration.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SigningKeyConfig {
+    id: String,
+    secret: Option<String>,
+    #[serde(default)]
+    status: SigningKeyStatus,
+}
+
+/// Errors when loading a [`CsrfKeyring`] from the configuration.
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum CsrfKeyringError {
+    #[error("{0}")]
+    Config(Box<rocket::figment::Error>),
+    #[error("key IDs must be unique and must not be empty or contain `.`, got `{0}`")]
+    InvalidKeyId(String),
+    #[error("key `{0}` has no secret")]
+    MissingSecret(String),
+    #[error("the secret of key `{0}` must be 256 bit base64 or hex, like `secret_key`")]
+    InvalidSecret(String),
+    #[error("expected exactly one primary key, found {0}")]
+    PrimaryKeyCount(usize),
+    #[error("`secret_key` must be 256 bit base64 or hex, or at least 32 bytes")]
+    InvalidSecretKey,
+    #[error("could not generate a random key: {0}")]
+    Random(#[from] rand::Error),
+}
+
+struct KeyringEntry {
+    id: String,
+    /// `None` for retired keys whose secret was removed.
+    key: Option<CsrfSigningKey>,
+    status: SigningKeyStatus,
+}
+
+/// The keys used to sign and verify [`crate::SignedCsrfToken`]s and
+/// [`crate::XsrfCookieCsrfToken`]s, so that keys can be rotated without invalidating every
+/// token at once.
+///
+/// Tokens are prefixed with the ID of the key which signed them (`key_id.issued_at.nonce.mac`).
+/// New tokens are always signed with the primary key, while tokens signed with any key which
+/// is not retired are accepted until they expire. To rotate keys, add a new key as a
+/// verification key everywhere, then make it the primary, and finally retire the old key
+/// once its tokens have expired.
+///
+/// Keys are read from `csrf.signing_keys` by [`CsrfSigningKey::fairing`], e.g. in `Rocket.toml`:
+///
+/// ```toml
+/// [[default.csrf.signing_keys]]
+/// id = "2024-06"
+/// secret = "..."
+/// status = "primary"
+///
+/// [[default.csrf.signing_keys]]
+/// id = "2024-01"
+/// secret = "..."
+/// status = "verify"
+///
+/// [[default.csrf.signing_keys]]
+/// id = "2023-06"
+/// secret = "..."
+/// status = "retired"
+/// ```
+///
+/// Secrets are decoded like Rocket's `secret_key`, so they must be base64 (44 or 88
+/// characters) or hex (64 characters), e.g. from `openssl rand -base64 32`.
+///
+/// If no keys are configured, a single primary key with the ID [`DEFAULT_SIGNING_KEY_ID`] is
+/// derived from Rocket's `secret_key`.
+#[derive(Clone)]
+pub struct CsrfKeyring {
+    keys: Arc<[KeyringEntry]>,
+}
+
+impl CsrfKeyring {
+    pub(crate) fn from_rocket(rocket: &Rocket<Build>) -> Result<Self, CsrfKeyringError> {
+        let figment = rocket.figment();
+        if figment.find_value(SIGNING_KEYS_CONFIG_KEY).is_err() {
+            return Ok(Self {
+                keys: Arc::new([KeyringEntry {
+                    id: DEFAULT_SIGNING_KEY_ID.to_owned(),
+                    key: Some(CsrfSigningKey::from_rocket(rocket)?),
+                    status: SigningKeyStatus::Primary,
+                }]),
+            });
+        }
+        let configs: Vec<SigningKeyConfig> = figment
+            .extract_inner(SIGNING_KEYS_CONFIG_KEY)
+            .map_err(|e| CsrfKeyringError::Config(Box::new(e)))?;
+        let mut keys: Vec<KeyringEntry> = Vec::with_capacity(configs.len());
+        for config in configs {
+            if config.id.is_empty()
+                || config.id.contains('.')
+                || keys.iter().any(|entry| entry.id == config.id)
+            {
+                return Err(CsrfKeyringError::InvalidKeyId(config.id));
+            }
+            let key = match (config.status, config.secret) {
+                (_, Some(secret)) if !secret.is_empty() => Some(
+                    CsrfSigningKey::from_secret(&secret)
+                        .ok_or_else(|| CsrfKeyringError::InvalidSecret(config.id.clone()))?,
+                ),
+                (SigningKeyStatus::Retired, _) => None,
+                _ => return Err(CsrfKeyringError::MissingSecret(config.id)),
+            };
+            keys.push(KeyringEntry {
+                id: config.id,
+                key,
+                status: config.status,
+            });
+        }
+        let primary_keys = keys
+            .iter()
+            .filter(|entry| entry.status == SigningKeyStatus::Primary)
+            .count();
+        if primary_keys != 1 {
+            return Err(CsrfKeyringError::PrimaryKeyCount(primary_keys));
+        }
+        Ok(Self { keys: keys.into() })
+    }
+
+    /// The ID of the key which signs new tokens.
+    pub fn primary_key_id(&self) -> &str {
+        &self.primary().id
+    }
+
+    /// The IDs of all keys, and what they are used for.
+    pub fn key_ids(&self) -> impl Iterator<Item = (&str, SigningKeyStatus)> {
+        self.keys
+            .iter()
+            .map(|entry| (entry.id.as_str(), entry.status))
+    }
+
+    fn primary(&self) -> &KeyringEntry {
+        self.keys
+            .iter()
+            .find(|entry| entry.status == SigningKeyStatus::Primary)
+            .expect("keyring has a primary key")
+    }
+
+    /// Signs a new token with the primary key, using `sign` to compute the MAC.
+    pub(crate) fn issue<F>(&self, nonce: &str, sign: F) -> String
+    where
+        F: Fn(&CsrfSigningKey, i64, &str) -> Vec<u8>,
+    {
+        let primary = self.primary();
+        let key = primary.key.as_ref().expect("primary key has a secret");
+        let issued_at = now_unix_seconds();
+        let mac = sign(key, issued_at, nonce);
+        format!(
+            "{}.{issued_at}.{nonce}.{}",
+            primary.id,
+            base64::encode_config(mac, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Checks a token issued by [`Self::issue`] with the same `sign`, returning the ID of the
+    /// key which signed it.
+    pub(crate) fn verify<F>(
+        &self,
+        token: &str,
+        max_age: i64,
+        sign: F,
+    ) -> Result<&str, CsrfTokenVerificationError>
+    where
+        F: Fn(&CsrfSigningKey, i64, &str) -> Vec<u8>,
+    {
+        let parts: Vec<_> = token.split('.').collect();
+        let [key_id, issued_at, nonce, mac] = parts[..] else {
+            return Err(CsrfTokenVerificationError::CsrfTokenMismatch);
+        };
+        let entry = self
+            .keys
+            .iter()
+            .find(|entry| entry.id == key_id)
+            .ok_or(CsrfTokenVerificationError::CsrfTokenMismatch)?;
+        // Without the secret, a token claiming to be from a retired key may well be forged.
+        let key = entry
+            .key
+            .as_ref()
+            .ok_or(CsrfTokenVerificationError::CsrfTokenMismatch)?;
+        let issued_at = issued_at
+            .parse::<i64>()
+            .map_err(|_| CsrfTokenVerificationError::CsrfTokenMismatch)?;
+        let mac = base64::decode_config(mac, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| CsrfTokenVerificationError::CsrfTokenMismatch)?;
+        if !constant_time_eq(&mac, &sign(key, issued_at, nonce)) {
+            Err(CsrfTokenVerificationError::CsrfTokenMismatch)
+        } else if entry.status == SigningKeyStatus::Retired {
+            Err(CsrfTokenVerificationError::RetiredKey)
+        } else if is_expired(issued_at, max_age) {
+            Err(CsrfTokenVerificationError::Expired)
+        } else {
+            Ok(&entry.id)
+        }
+    }
+}
+
+impl std::fmt::Debug for CsrfKeyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.key_ids()).finish()
+    }
+}
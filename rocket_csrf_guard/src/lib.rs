@@ -1,16 +1,41 @@
 //! Ergonomic CSRF protection for Rocket applications.
 //!
 //! The main macro [`with_csrf_token`] enables CSRF protection for a given [`rocket::form::Form`].
-//! Slap on a double submit cookie or a session based CSRF token and you're good to go.
+//! Slap on a double submit cookie, a session based CSRF token or a stateless
+//! [`SignedCsrfToken`] and you're good to go. For tokens which can only be used once,
+//! see [`OneTimeCsrfToken`].
+//! To protect every unsafe request without touching individual routes, attach a [`CsrfFairing`].
+//! [`CsrfAudit`] lists the routes which are left unprotected at launch.
+//! Register [`CsrfErrorResponse::catcher`] to tell users and API clients why a request was
+//! rejected.
+//! Every issued token and every check is reported to [`CsrfEventListener`]s, and counted by
+//! [`CsrfMetrics`]; enable the `tracing` feature to log them with `TracingCsrfEventListener`.
 //! Look at the examples/ folder for more detailed examples of all the functionality in a test app.
 
+mod audit;
+mod config;
 mod cookie;
+mod event;
+mod extract;
+mod fairing;
+mod fetch_metadata;
 mod form;
 mod header;
+mod keyring;
+mod one_time;
+mod origin;
+mod preverified;
 mod proof;
+mod recovery;
+mod report;
+mod response;
+mod rotation;
+mod scoped;
+mod signed;
 mod token;
 mod util;
 mod verifier;
+mod xsrf;
 
 #[cfg(test)]
 extern crate rocket;
@@ -37,19 +62,52 @@ mod tests;
 /// For more detailed examples, look at the `derive_` examples in the examples/ folder.
 pub use rocket_csrf_guard_derive::with_csrf_token;
 
+pub use audit::{AuditedRoute, CsrfAudit, CsrfAuditMode, CsrfAuditReport, RouteCsrfProtection};
+pub use config::{CsrfConfig, DoubleSubmitCookiePolicy, CSRF_FIELD_NAME, CSRF_TOKEN_LENGTH};
 pub use cookie::{
-    DoubleSubmitCookieCsrfToken, SetDoubleSubmitCookieCsrfToken, SetLaxDoubleSubmitCookieCsrfToken,
+    DoubleSubmitCookieCsrfToken, SetDoubleSubmitCookieCsrfToken,
+    SetDoubleSubmitCookieCsrfTokenImpl, SetLaxDoubleSubmitCookieCsrfToken,
     SetNoneDoubleSubmitCookieCsrfToken_DO_NOT_USE_UNLESS_YOU_ARE_SURE,
-    DOUBLE_SUBMIT_CSRF_TOKEN_COOKIE_NAME,
+    DOUBLE_SUBMIT_CSRF_TOKEN_COOKIE_NAME, DOUBLE_SUBMIT_CSRF_TOKEN_EXPIRY_SECONDS,
+    DOUBLE_SUBMIT_CSRF_TOKEN_NONE_EXPIRY_SECONDS,
+};
+#[cfg(feature = "tracing")]
+pub use event::TracingCsrfEventListener;
+pub use event::{CsrfEvent, CsrfEventKind, CsrfEventListener, CsrfMechanism, CsrfMetrics};
+pub use fairing::CsrfFairing;
+pub use fetch_metadata::{
+    FetchMetadataPolicy, FetchMetadataPolicyError, FetchMetadataPolicyImpl,
+    NavigationalFetchMetadataPolicy,
 };
 pub use form::{CsrfProtectedForm, CsrfProtectedFormError, CsrfProtectedFormWithGuard};
 pub use header::{
     CheckCsrfProtectionHeader, CheckCsrfProtectionHeaderError, CsrfTokenSourcedFromHeader,
+    CSRF_HEADER_NAME,
+};
+pub use keyring::{CsrfKeyring, SigningKeyStatus, DEFAULT_SIGNING_KEY_ID};
+pub use one_time::{InMemoryNonceStore, NonceStatus, NonceStore, OneTimeCsrfToken};
+pub use origin::{
+    CheckSameOrigin, CheckSameOriginError, MissingOriginPolicy, OriginSourcedFromRequest,
+    SameOriginVerifier,
 };
+pub use preverified::{CsrfPreverifiedForm, CsrfPreverifiedFormError, CsrfProtectedMultipartForm};
 pub use proof::CsrfCheckProof;
+pub use recovery::{CsrfRecoverableForm, CsrfResubmitForm, CsrfResubmittableForm};
+pub use report::{CsrfViolation, CsrfViolationReason};
+pub use response::CsrfErrorResponse;
+pub use rotation::{RotatingCsrfToken, CSRF_TOKEN_ROTATION_GRACE_SECONDS};
+pub use scoped::{csrf_action_scope, ActionScopedCsrfToken, CsrfActionScope, MatchedRoute};
+pub use signed::{
+    CsrfSessionBinding, CsrfSigningKey, SignedCsrfToken, SIGNED_CSRF_TOKEN_MAX_AGE_SECONDS,
+};
 pub use token::{
     ManuallySourcedCsrfToken_DO_NOT_USE_UNLESS_YOU_ARE_SURE, WithUserProvidedCsrfToken,
 };
 pub use verifier::{CsrfTokenVerificationError, CsrfTokenVerifier, VerifierWithKnownExpectedToken};
+pub use xsrf::{SetXsrfCookieCsrfToken, XsrfCookieCsrfToken, XSRF_COOKIE_NAME};
 
 pub type DoubleSubmitCookieCsrfProtectedForm<F> = CsrfProtectedForm<DoubleSubmitCookieCsrfToken, F>;
+
+/// A [`CsrfProtectedForm`] for JSON request bodies, where `T` provides the csrf token.
+pub type CsrfProtectedJson<V, T> = CsrfProtectedForm<V, rocket::serde::json::Json<T>>;
+pub type DoubleSubmitCookieCsrfProtectedJson<T> = CsrfProtectedJson<DoubleSubmitCookieCsrfToken, T>;
@@ -0,0 +1,248 @@
+use crate::{
+    config::CsrfConfig,
+    event::{CsrfEventEmitter, CsrfEventKind},
+    report::CsrfViolationReason,
+    signed::CsrfSessionBinding,
+    util::{is_expired, now_unix_seconds, random_id},
+    verifier::sealed::ReportOnlyCheck,
+    CsrfCheckProof, CsrfTokenVerificationError, CsrfTokenVerifier, WithUserProvidedCsrfToken,
+};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    State,
+};
+
+/// How often [`InMemoryNonceStore`] sweeps out expired tokens, in seconds.
+const NONCE_EVICTION_INTERVAL_SECONDS: i64 = 60;
+
+/// What a [`NonceStore`] knew about a token when it was consumed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonceStatus {
+    /// The token was issued for the session, has not expired, and had not been used before.
+    Fresh,
+    /// The token was issued for the session, but has already been used.
+    AlreadyUsed,
+    /// The token was never issued for the session, or has expired.
+    Unknown,
+}
+
+/// Storage for the tokens issued by [`OneTimeCsrfToken`].
+///
+/// Implement this on top of your database or cache to share tokens between instances.
+/// The store must be added to managed state, and is cloned for every request, so it should
+/// be a cheap handle to the underlying storage.
+#[async_trait::async_trait]
+pub trait NonceStore: Clone + Send + Sync + 'static {
+    /// Remembers a newly issued token for the session, until `expires_at` (in seconds since
+    /// the unix epoch).
+    async fn insert(&self, session_id: &str, token: &str, expires_at: i64) -> anyhow::Result<()>;
+
+    /// Marks the token as used, returning what state it was in beforehand.
+    /// This must be atomic, so that concurrent requests cannot both consume the same token.
+    /// Used tokens should be remembered until they expire, so that replays are reported as
+    /// [`NonceStatus::AlreadyUsed`].
+    async fn consume(&self, session_id: &str, token: &str) -> anyhow::Result<NonceStatus>;
+}
+
+/// A [`NonceStore`] which keeps tokens in memory, evicting them once they expire.
+///
+/// Tokens are lost on restart and are not shared between instances, so only use this if
+/// you run a single instance of your app.
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryNonceStore {
+    inner: Arc<Mutex<InMemoryNonces>>,
+}
+
+#[derive(Debug, Default)]
+struct InMemoryNonces {
+    /// Maps `(session_id, token)` to when the token expires and whether it was used.
+    tokens: HashMap<(String, String), (i64, bool)>,
+    next_eviction: i64,
+}
+
+impl InMemoryNonces {
+    fn evict_expired(&mut self, now: i64) {
+        if now < self.next_eviction {
+            return;
+        }
+        self.tokens.retain(|_, (expires_at, _)| *expires_at >= now);
+        self.next_eviction = now + NONCE_EVICTION_INTERVAL_SECONDS;
+    }
+}
+
+impl InMemoryNonceStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of tokens currently held, including used ones which have not expired yet.
+    pub fn len(&self) -> usize {
+        self.lock().tokens.len()
+    }
+
+    /// Whether the store holds no tokens.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, InMemoryNonces> {
+        // Nothing can panic while the lock is held, so it is never poisoned in practice.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait::async_trait]
+impl NonceStore for InMemoryNonceStore {
+    async fn insert(&self, session_id: &str, token: &str, expires_at: i64) -> anyhow::Result<()> {
+        let mut nonces = self.lock();
+        nonces.evict_expired(now_unix_seconds());
+        nonces.tokens.insert(
+            (session_id.to_owned(), token.to_owned()),
+            (expires_at, false),
+        );
+        Ok(())
+    }
+
+    async fn consume(&self, session_id: &str, token: &str) -> anyhow::Result<NonceStatus> {
+        let now = now_unix_seconds();
+        let mut nonces = self.lock();
+        nonces.evict_expired(now);
+        let key = (session_id.to_owned(), token.to_owned());
+        Ok(match nonces.tokens.get_mut(&key) {
+            Some((expires_at, _)) if *expires_at < now => NonceStatus::Unknown,
+            Some((_, used)) if *used => NonceStatus::AlreadyUsed,
+            Some((_, used)) => {
+                *used = true;
+                NonceStatus::Fresh
+            }
+            None => NonceStatus::Unknown,
+        })
+    }
+}
+
+/// CSRF protection using tokens which can only be used once.
+///
+/// Every call to [`OneTimeCsrfToken::issue`] mints a new token, which is recorded in the
+/// [`NonceStore`] `N` (which must be in managed state) for the session provided by `S`
+/// (see [`CsrfSessionBinding`]). Verifying a token consumes it, so replaying it fails with
+/// [`CsrfTokenVerificationError::AlreadyUsed`]. Tokens older than
+/// [`CsrfSessionBinding::csrf_token_max_age_seconds`] are rejected as expired.
+///
+/// Use this as a request guard to issue tokens, and as the verifier for
+/// [`crate::CsrfProtectedForm`] or [`crate::CheckCsrfProtectionHeader`] to check them.
+pub struct OneTimeCsrfToken<S, N = InMemoryNonceStore> {
+    store: N,
+    session_id: String,
+    max_age: i64,
+    token_length: usize,
+    events: CsrfEventEmitter,
+    _marker: std::marker::PhantomData<fn() -> S>,
+}
+
+impl<S, N> OneTimeCsrfToken<S, N>
+where
+    N: NonceStore,
+{
+    /// Mints a new token for the current session and records it in the store.
+    pub async fn issue(&self) -> anyhow::Result<String> {
+        let issued_at = now_unix_seconds();
+        let token = format!("{issued_at}.{}", random_id(self.token_length)?);
+        self.store
+            .insert(
+                &self.session_id,
+                &token,
+                issued_at.saturating_add(self.max_age),
+            )
+            .await?;
+        self.events.emit(CsrfEventKind::TokenIssued {
+            verifier: "OneTimeCsrfToken",
+        });
+        Ok(token)
+    }
+}
+
+impl<S, N> std::fmt::Debug for OneTimeCsrfToken<S, N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OneTimeCsrfToken")
+            .field("session_id", &self.session_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Verifies that the received token was issued for the current session and not used before.
+#[async_trait::async_trait]
+impl<S, N> CsrfTokenVerifier for OneTimeCsrfToken<S, N>
+where
+    N: NonceStore,
+{
+    type Proof = CsrfCheckProof;
+    type Error = CsrfTokenVerificationError;
+
+    async fn verify(
+        &self,
+        token: &(dyn WithUserProvidedCsrfToken + Send + Sync),
+    ) -> Result<Self::Proof, Self::Error> {
+        let mechanism = token.csrf_mechanism();
+        let token = token.csrf_token();
+        let issued_at = token
+            .split_once('.')
+            .and_then(|(issued_at, _)| issued_at.parse::<i64>().ok())
+            .ok_or(CsrfTokenVerificationError::CsrfTokenMismatch)?;
+        if is_expired(issued_at, self.max_age) {
+            return Err(CsrfTokenVerificationError::Expired);
+        }
+        match self.store.consume(&self.session_id, token).await {
+            Ok(NonceStatus::Fresh) => Ok(CsrfCheckProof::new::<Self>(mechanism)),
+            Ok(NonceStatus::AlreadyUsed) => Err(CsrfTokenVerificationError::AlreadyUsed),
+            Ok(NonceStatus::Unknown) => Err(CsrfTokenVerificationError::CsrfTokenMismatch),
+            Err(e) => Err(CsrfTokenVerificationError::Unknown(e.into())),
+        }
+    }
+
+    fn violation_reason(error: &Self::Error) -> CsrfViolationReason {
+        error.into()
+    }
+
+    fn report_only_proof(check: ReportOnlyCheck) -> Option<Self::Proof> {
+        Some(CsrfCheckProof::excused::<Self>(check.mechanism))
+    }
+}
+
+/// Fetches the session binding and nonce store for the current request.
+#[async_trait::async_trait]
+impl<'r, S, N> FromRequest<'r> for OneTimeCsrfToken<S, N>
+where
+    S: CsrfSessionBinding + FromRequest<'r>,
+    N: NonceStore,
+{
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (session_id, max_age) = match request.guard::<S>().await {
+            Outcome::Success(session) => (
+                session.csrf_session_id().to_owned(),
+                session.csrf_token_max_age_seconds(),
+            ),
+            Outcome::Error((status, _)) | Outcome::Forward(status) => {
+                return Outcome::Forward(status)
+            }
+        };
+        let Some(store) = request.guard::<&State<N>>().await.succeeded() else {
+            return Outcome::Forward(Status::InternalServerError);
+        };
+        Outcome::Success(Self {
+            store: store.inner().clone(),
+            session_id,
+            max_age,
+            token_length: CsrfConfig::from_request(request).token_length,
+            events: CsrfEventEmitter::for_request(request),
+            _marker: std::marker::PhantomData,
+        })
+    }
+}
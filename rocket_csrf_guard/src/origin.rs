@@ -0,0 +1,272 @@
+use crate::{
+    config::CsrfConfig,
+    event::{emit, CsrfEventKind, CsrfMechanism},
+    proof::CsrfCheckProof,
+    report::{report_only_check_proof, CsrfViolationReason},
+    token::WithUserProvidedCsrfToken,
+    util::set_proof_in_cache,
+    verifier::sealed::ReportOnlyCheck,
+    verifier::CsrfTokenVerificationError,
+    verifier::CsrfTokenVerifier,
+};
+
+use rocket::{
+    http::{uri::Absolute, Status},
+    request::{self, FromRequest, Request},
+    serde::Deserialize,
+};
+
+/// What [`CheckSameOrigin`] does with requests which have no `Origin` header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum MissingOriginPolicy {
+    /// Check the origin of the `Referer` header instead, rejecting the request if that is
+    /// missing too.
+    #[default]
+    CheckReferer,
+    /// Reject the request.
+    Reject,
+    /// Check the `Referer` header if present, otherwise accept the request.
+    /// Some privacy tools strip both headers, but so can attackers in some browsers,
+    /// so only use this alongside a token based check. Requests accepted without either
+    /// header do not get a [`CsrfCheckProof`].
+    Allow,
+}
+
+/// The scheme, host and port of a URL, e.g. `https://example.com:8443`.
+#[derive(Debug, PartialEq, Eq)]
+struct SiteOrigin {
+    scheme: String,
+    host: String,
+    port: Option<u16>,
+}
+
+impl SiteOrigin {
+    /// Parses the origin out of an absolute URL, such as an `Origin` or `Referer` header.
+    fn parse(url: &str) -> Option<Self> {
+        let url = Absolute::parse(url).ok()?;
+        let authority = url.authority()?;
+        let scheme = url.scheme().to_ascii_lowercase();
+        let port = authority.port().or_else(|| default_port(&scheme));
+        Some(Self {
+            host: authority.host().to_ascii_lowercase(),
+            scheme,
+            port,
+        })
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+/// An entry of [`CsrfConfig::allowed_origins`], such as `https://example.com` or
+/// `https://*.example.com`.
+#[derive(Debug)]
+pub(crate) struct AllowedOrigin {
+    origin: SiteOrigin,
+    any_subdomain: bool,
+}
+
+impl AllowedOrigin {
+    pub(crate) fn parse(pattern: &str) -> Option<Self> {
+        let (scheme, rest) = pattern.split_once("://")?;
+        match rest.strip_prefix("*.") {
+            Some(rest) => Some(Self {
+                origin: SiteOrigin::parse(&format!("{scheme}://{rest}"))?,
+                any_subdomain: true,
+            }),
+            None => Some(Self {
+                origin: SiteOrigin::parse(pattern)?,
+                any_subdomain: false,
+            }),
+        }
+    }
+
+    fn matches(&self, origin: &SiteOrigin) -> bool {
+        let host_matches = origin.host == self.origin.host
+            || (self.any_subdomain
+                && origin
+                    .host
+                    .strip_suffix(&self.origin.host)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')));
+        origin.scheme == self.origin.scheme && origin.port == self.origin.port && host_matches
+    }
+}
+
+/// Checks that a request came from one of the app's own origins.
+///
+/// The "token" is the origin the browser reported, as found by [`CheckSameOrigin`].
+/// It must match one of [`CsrfConfig::allowed_origins`], where `https://*.example.com`
+/// matches any subdomain of `example.com` (but not `example.com` itself).
+/// If no origins are configured, the origin's host and port must match the `Host` header.
+/// The scheme cannot be checked in that case, since TLS is often terminated by a proxy.
+pub struct SameOriginVerifier {
+    allowed: Vec<AllowedOrigin>,
+    host: Option<(String, Option<u16>)>,
+}
+
+impl SameOriginVerifier {
+    fn for_request(request: &Request<'_>) -> Self {
+        let config = CsrfConfig::from_request(request);
+        Self {
+            // Invalid entries fail launch in `CsrfConfig::fairing`.
+            allowed: config
+                .allowed_origins
+                .iter()
+                .filter_map(|pattern| AllowedOrigin::parse(pattern))
+                .collect(),
+            host: request
+                .host()
+                .map(|host| (host.domain().as_str().to_ascii_lowercase(), host.port())),
+        }
+    }
+
+    fn is_allowed(&self, origin: &SiteOrigin) -> bool {
+        if !self.allowed.is_empty() {
+            return self.allowed.iter().any(|allowed| allowed.matches(origin));
+        }
+        self.host.as_ref().is_some_and(|(host, port)| {
+            *host == origin.host && port.or_else(|| default_port(&origin.scheme)) == origin.port
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl CsrfTokenVerifier for SameOriginVerifier {
+    type Proof = CsrfCheckProof;
+    type Error = CsrfTokenVerificationError;
+
+    async fn verify(
+        &self,
+        token: &(dyn WithUserProvidedCsrfToken + Send + Sync),
+    ) -> Result<Self::Proof, Self::Error> {
+        match SiteOrigin::parse(token.csrf_token()) {
+            Some(origin) if self.is_allowed(&origin) => {
+                Ok(CsrfCheckProof::new::<Self>(token.csrf_mechanism()))
+            }
+            _ => Err(CsrfTokenVerificationError::CsrfTokenMismatch),
+        }
+    }
+
+    fn violation_reason(error: &Self::Error) -> CsrfViolationReason {
+        error.into()
+    }
+
+    fn report_only_proof(check: ReportOnlyCheck) -> Option<Self::Proof> {
+        Some(CsrfCheckProof::excused::<Self>(check.mechanism))
+    }
+}
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for SameOriginVerifier {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(Self::for_request(request))
+    }
+}
+
+/// Errors when validating a [`CheckSameOrigin`]
+#[derive(Debug)]
+pub enum CheckSameOriginError {
+    /// The request had no `Origin` (or `Referer`, depending on [`MissingOriginPolicy`]) header.
+    NoOriginPresent,
+    /// The request came from an origin which is not allowed.
+    OriginMismatch,
+}
+
+/// The origin of a request, as reported by the browser.
+pub struct OriginSourcedFromRequest<'r>(&'r str);
+
+impl<'r> WithUserProvidedCsrfToken for OriginSourcedFromRequest<'r> {
+    fn csrf_token(&self) -> &str {
+        self.0
+    }
+
+    fn csrf_mechanism(&self) -> CsrfMechanism {
+        CsrfMechanism::Origin
+    }
+}
+
+/// A request guard which verifies that a request came from one of the app's own origins,
+/// using the `Origin` header, or the `Referer` header as configured by
+/// [`CsrfConfig::missing_origin`]. See [`SameOriginVerifier`] for how origins are matched.
+///
+/// When the origin is checked and matches, [`CsrfCheckProof`] is put in the request local
+/// cache, just like [`crate::CheckCsrfProtectionHeader`] does. Requests let through by
+/// [`MissingOriginPolicy::Allow`] get no proof.
+/// This is recommended by OWASP as defense in depth, on top of token based checks.
+#[derive(Debug)]
+pub struct CheckSameOrigin(());
+
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for CheckSameOrigin {
+    type Error = CheckSameOriginError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let policy = CsrfConfig::from_request(request).missing_origin;
+        let headers = request.headers();
+        let origin = match (headers.get_one("Origin"), policy) {
+            (Some(origin), _) => Some(origin),
+            (None, MissingOriginPolicy::Reject) => None,
+            (None, _) => headers.get_one("Referer"),
+        };
+        let Some(origin) = origin else {
+            // Nothing was checked, so there is no proof to hand out; the token based check
+            // this policy must be paired with provides one.
+            if policy == MissingOriginPolicy::Allow {
+                return request::Outcome::Success(Self(()));
+            }
+            return Self::fail(
+                request,
+                CheckSameOriginError::NoOriginPresent,
+                CsrfViolationReason::MissingOrigin,
+            );
+        };
+        let verifier = SameOriginVerifier::for_request(request);
+        match verifier.verify(&OriginSourcedFromRequest(origin)).await {
+            Ok(proof) => {
+                Self::verified(request);
+                set_proof_in_cache(request, proof);
+                request::Outcome::Success(Self(()))
+            }
+            Err(_) => Self::fail(
+                request,
+                CheckSameOriginError::OriginMismatch,
+                CsrfViolationReason::OriginMismatch,
+            ),
+        }
+    }
+}
+
+impl CheckSameOrigin {
+    fn verified(request: &Request<'_>) {
+        emit(
+            request,
+            CsrfEventKind::Verified {
+                mechanism: CsrfMechanism::Origin,
+            },
+        );
+    }
+
+    /// Rejects the request, unless failures are only being reported.
+    fn fail(
+        request: &Request<'_>,
+        error: CheckSameOriginError,
+        reason: CsrfViolationReason,
+    ) -> request::Outcome<Self, CheckSameOriginError> {
+        match report_only_check_proof::<SameOriginVerifier>(request, CsrfMechanism::Origin, reason)
+        {
+            Some(proof) => {
+                set_proof_in_cache(request, proof);
+                request::Outcome::Success(Self(()))
+            }
+            None => request::Outcome::Error((Status::Forbidden, error)),
+        }
+    }
+}
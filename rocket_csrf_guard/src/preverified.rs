@@ -0,0 +1,177 @@
+use crate::{
+    event::{emit, CsrfEventKind, CsrfMechanism},
+    extract::csrf_token_from_request,
+    report::{report_only_proof, CsrfViolationReason},
+    token::WithUserProvidedCsrfToken,
+    util::set_proof_in_cache,
+    verifier::CsrfTokenVerifier,
+};
+
+use std::ops::{Deref, DerefMut};
+
+use rocket::{
+    data::{self, Data, FromData},
+    form::Form,
+    http::Status,
+    request::{self, FromRequest, Request},
+};
+
+/// Errors when validating a [`CsrfPreverifiedForm`]
+#[derive(Debug)]
+pub enum CsrfPreverifiedFormError<T> {
+    /// There was no valid instance of a [`CsrfTokenVerifier`] to validate the provided token against.
+    NoVerifierFound,
+    /// The token was neither in the header nor at the start of the body.
+    NoCsrfTokenFound,
+    /// There was an error verifying the token itself, perhaps because it was incorrect.
+    /// Intentionally an opaque type so error messages cannot contain the token.
+    CsrfTokenVerificationError,
+    /// An error occurred while parsing the form.
+    FormParsing(T),
+}
+
+/// A wrapper form which checks the csrf token *before* parsing the form, unlike
+/// [`crate::CsrfProtectedForm`], so forged requests are rejected after reading at most the start of the body.
+///
+/// The token is read from the configured header (see [`crate::CsrfConfig::header_name`]),
+/// or failing that from the configured field at the start of the body: only the first 512
+/// bytes of a urlencoded form, multipart form or JSON body are looked at. For multipart
+/// forms the token must be the very first part. Browsers send fields in document order,
+/// so put the hidden input at the top of the form. Tokens are never read from the query
+/// string, since URLs end up in logs and `Referer` headers.
+///
+/// Once the token is verified the body is streamed to `F` as usual, which means large
+/// uploads of [`rocket::fs::TempFile`]s are only written to disk for valid requests.
+/// `F` does not need to implement [`crate::WithUserProvidedCsrfToken`].
+pub struct CsrfPreverifiedForm<V, F>
+where
+    V: CsrfTokenVerifier,
+{
+    form: F,
+    proof: V::Proof,
+    _marker: std::marker::PhantomData<V>,
+}
+
+impl<V, F> CsrfPreverifiedForm<V, F>
+where
+    V: CsrfTokenVerifier,
+{
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn into_inner(self) -> F {
+        self.form
+    }
+}
+
+impl<V, F> CsrfPreverifiedForm<V, Form<F>>
+where
+    V: CsrfTokenVerifier,
+{
+    /// Extracts the inner form, throwing away the proof.
+    pub fn into_innermost(self) -> F {
+        self.form.into_inner()
+    }
+
+    /// Extracts the inner form and proof.
+    pub fn into_parts(self) -> (V::Proof, F) {
+        (self.proof, self.form.into_inner())
+    }
+}
+
+#[async_trait::async_trait]
+impl<'r, V, F> FromData<'r> for CsrfPreverifiedForm<V, F>
+where
+    V: CsrfTokenVerifier + FromRequest<'r> + Send + Sync,
+    V::Proof: Clone,
+    F: FromData<'r> + Sized + Send + Sync,
+{
+    type Error = CsrfPreverifiedFormError<<F as FromData<'r>>::Error>;
+
+    async fn from_data(request: &'r Request<'_>, mut data: Data<'r>) -> data::Outcome<'r, Self> {
+        let verifier = match request.guard::<V>().await {
+            request::Outcome::Success(verifier) => Ok(verifier),
+            request::Outcome::Error((status, _)) => Err(status),
+            request::Outcome::Forward(status) => return data::Outcome::Forward((data, status)),
+        };
+        let token = csrf_token_from_request(request, &mut data).await;
+        let mechanism = token
+            .as_ref()
+            .map_or(CsrfMechanism::Header, |token| token.csrf_mechanism());
+        let (status, error, reason) = match (verifier, token) {
+            (Ok(verifier), Some(token)) => match verifier.verify(&token).await {
+                Ok(proof) => {
+                    emit(request, CsrfEventKind::Verified { mechanism });
+                    return Self::parse(request, data, proof).await;
+                }
+                Err(e) => (
+                    Status::Forbidden,
+                    CsrfPreverifiedFormError::CsrfTokenVerificationError,
+                    V::violation_reason(&e),
+                ),
+            },
+            (Err(status), _) => (
+                status,
+                CsrfPreverifiedFormError::NoVerifierFound,
+                CsrfViolationReason::NoVerifier,
+            ),
+            (Ok(_), None) => (
+                Status::Forbidden,
+                CsrfPreverifiedFormError::NoCsrfTokenFound,
+                CsrfViolationReason::MissingToken,
+            ),
+        };
+        match report_only_proof::<V>(request, mechanism, reason) {
+            Some(proof) => Self::parse(request, data, proof).await,
+            None => data::Outcome::Error((status, error)),
+        }
+    }
+}
+
+impl<'r, V, F> CsrfPreverifiedForm<V, F>
+where
+    V: CsrfTokenVerifier,
+    V::Proof: Clone,
+    F: FromData<'r>,
+{
+    /// Streams the body to `F` once the token has been checked.
+    async fn parse(
+        request: &'r Request<'_>,
+        data: Data<'r>,
+        proof: V::Proof,
+    ) -> data::Outcome<'r, Self, CsrfPreverifiedFormError<F::Error>> {
+        set_proof_in_cache(request, proof.clone());
+        match F::from_data(request, data).await {
+            data::Outcome::Success(form) => data::Outcome::Success(Self {
+                form,
+                proof,
+                _marker: std::marker::PhantomData,
+            }),
+            data::Outcome::Error((status, e)) => {
+                data::Outcome::Error((status, CsrfPreverifiedFormError::FormParsing(e)))
+            }
+            data::Outcome::Forward(f) => data::Outcome::Forward(f),
+        }
+    }
+}
+
+impl<V, F> Deref for CsrfPreverifiedForm<V, F>
+where
+    V: CsrfTokenVerifier,
+{
+    type Target = F;
+
+    fn deref(&self) -> &Self::Target {
+        &self.form
+    }
+}
+
+impl<V, F> DerefMut for CsrfPreverifiedForm<V, F>
+where
+    V: CsrfTokenVerifier,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.form
+    }
+}
+
+/// A [`CsrfPreverifiedForm`] for `multipart/form-data` uploads, where the token is the first part.
+pub type CsrfProtectedMultipartForm<V, F> = CsrfPreverifiedForm<V, F>;
@@ -1,17 +1,137 @@
+use crate::{event::CsrfMechanism, util::now_unix_seconds};
+
 use rocket::request::{FromRequest, Outcome, Request};
 
 /// A proof that a request has passed CSRF checks.
 /// Useful for constructing secure by default frameworks, [as seen in this blogpost](https://mhlakhani.com/blog/2024/01/on-secure-by-default-frameworks/)
+///
+/// Holding one is what matters, but it also records how the request was checked, so service
+/// layers can apply stricter policies to some actions, e.g. only accepting form tokens for
+/// money transfers, and refusing requests which failed their check but were let through in
+/// report-only mode:
+///
+/// ```rust
+/// # use rocket_csrf_guard::{CsrfCheckProof, CsrfMechanism};
+/// fn transfer_money(proof: &CsrfCheckProof) -> Result<(), &'static str> {
+///     if proof.report_only() {
+///         return Err("the CSRF check failed");
+///     }
+///     if proof.mechanism() != CsrfMechanism::FormField {
+///         return Err("transfers must be submitted from a form");
+///     }
+///     // ...
+///     Ok(())
+/// }
+/// ```
+///
+/// Proofs cannot be made up: they are only handed out by this crate's verifiers (and by
+/// [`crate::VerifierWithKnownExpectedToken`] implementations) when a check passes, and can
+/// then be fetched from the request local cache by using this type as a request guard.
+/// Verifiers which cannot use those should define their own proof type.
+/// Tests which need a proof without running a check can use
+/// [`CsrfCheckProof::forged_DO_NOT_USE_UNLESS_YOU_ARE_SURE`].
+/// Nothing else can make one. Its fields are private:
+///
+/// ```compile_fail,E0451
+/// # use rocket_csrf_guard::{CsrfCheckProof, CsrfMechanism};
+/// let proof = CsrfCheckProof {
+///     mechanism: CsrfMechanism::FormField,
+///     verifier: "mine",
+///     verified_at: 0,
+///     report_only: false,
+/// };
+/// ```
+///
+/// and so are the constructors verifiers use, for both passed checks:
+///
+/// ```compile_fail,E0624
+/// # use rocket_csrf_guard::{CsrfCheckProof, CsrfMechanism};
+/// let proof = CsrfCheckProof::new::<()>(CsrfMechanism::FormField);
+/// ```
+///
+/// and the failed ones let through in report-only mode:
+///
+/// ```compile_fail,E0624
+/// # use rocket_csrf_guard::{CsrfCheckProof, CsrfMechanism};
+/// let proof = CsrfCheckProof::excused::<()>(CsrfMechanism::FormField);
+/// ```
+///
+/// Verifiers hand out report-only proofs too, but only given a check which cannot be built
+/// outside this crate:
+///
+/// ```compile_fail,E0603
+/// # use rocket_csrf_guard::{CsrfMechanism, CsrfTokenVerifier, DoubleSubmitCookieCsrfToken};
+/// use rocket_csrf_guard::verifier::sealed::ReportOnlyCheck;
+///
+/// let check = ReportOnlyCheck {
+///     mechanism: CsrfMechanism::FormField,
+/// };
+/// let proof = DoubleSubmitCookieCsrfToken::report_only_proof(check);
+/// ```
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub enum CsrfCheckProof {
-    /// The request has passed CSRF checks.
-    /// This is the only valid value for this type.
-    PassedCsrfChecks,
+pub struct CsrfCheckProof {
+    mechanism: CsrfMechanism,
+    verifier: &'static str,
+    verified_at: i64,
+    report_only: bool,
 }
 
-impl Default for CsrfCheckProof {
-    fn default() -> Self {
-        Self::PassedCsrfChecks
+impl CsrfCheckProof {
+    /// A proof that the request was just checked by `V`, using `mechanism`.
+    pub(crate) fn new<V: ?Sized>(mechanism: CsrfMechanism) -> Self {
+        Self {
+            mechanism,
+            verifier: std::any::type_name::<V>(),
+            verified_at: now_unix_seconds(),
+            report_only: false,
+        }
+    }
+
+    /// A proof that the request failed a check by `V` using `mechanism`, but was let through
+    /// because the check is only reported, see [`crate::CsrfConfig::report_only`].
+    pub(crate) fn excused<V: ?Sized>(mechanism: CsrfMechanism) -> Self {
+        Self {
+            report_only: true,
+            ..Self::new::<V>(mechanism)
+        }
+    }
+
+    /// Makes a proof without checking anything, claiming that `mechanism` was used.
+    /// Its [`Self::verifier`] is `forged`.
+    ///
+    /// This is only meant for tests, e.g. to call service layers which take a proof.
+    /// Using it anywhere else turns off CSRF protection for whatever the proof is passed to.
+    #[allow(non_snake_case)]
+    pub fn forged_DO_NOT_USE_UNLESS_YOU_ARE_SURE(mechanism: CsrfMechanism) -> Self {
+        Self {
+            mechanism,
+            verifier: "forged",
+            verified_at: now_unix_seconds(),
+            report_only: false,
+        }
+    }
+
+    /// How the request was checked.
+    pub const fn mechanism(&self) -> CsrfMechanism {
+        self.mechanism
+    }
+
+    /// The type name of the verifier which checked the request, such as
+    /// `rocket_csrf_guard::cookie::DoubleSubmitCookieCsrfToken`.
+    pub const fn verifier(&self) -> &'static str {
+        self.verifier
+    }
+
+    /// When the request was checked, in seconds since the unix epoch.
+    pub const fn verified_at(&self) -> i64 {
+        self.verified_at
+    }
+
+    /// Whether the request actually failed the check, and was only let through because the
+    /// check is reported rather than enforced, see [`crate::CsrfConfig::report_only`].
+    /// Service layers which must never act on a forged request should refuse these.
+    pub const fn report_only(&self) -> bool {
+        self.report_only
     }
 }
 
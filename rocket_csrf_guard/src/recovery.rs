@@ -0,0 +1,168 @@
+use crate::{
+    cookie::{missing_cookie_reason, DoubleSubmitCookieCsrfToken, SetDoubleSubmitCookieCsrfToken},
+    event::{emit, CsrfEventKind},
+    form::{CsrfProtectedForm, CsrfProtectedFormError},
+    report::{report_only_proof, CsrfViolationReason},
+    token::WithUserProvidedCsrfToken,
+    util::set_proof_in_cache,
+    verifier::CsrfTokenVerifier,
+};
+
+use std::ops::Deref;
+
+use rocket::{
+    data::{self, Data, FromData},
+    form::Form,
+    http::Status,
+    request::{self, Request},
+    serde::json::Json,
+};
+
+/// A form which can be shown to the user again when its CSRF token is rejected, see
+/// [`CsrfRecoverableForm`].
+pub trait CsrfResubmittableForm {
+    /// Clears the fields which must not be sent back to the browser, such as passwords.
+    /// Everything else is kept so the user does not have to type it again.
+    fn clear_secret_fields(&mut self);
+}
+
+/// Convenience implementation for [`rocket::form::Form`], clearing the inner form's fields.
+impl<T> CsrfResubmittableForm for Form<T>
+where
+    T: CsrfResubmittableForm,
+{
+    fn clear_secret_fields(&mut self) {
+        (**self).clear_secret_fields();
+    }
+}
+
+/// Convenience implementation for [`rocket::serde::json::Json`], clearing the inner value's fields.
+impl<T> CsrfResubmittableForm for Json<T>
+where
+    T: CsrfResubmittableForm,
+{
+    fn clear_secret_fields(&mut self) {
+        (**self).clear_secret_fields();
+    }
+}
+
+/// An opt-in alternative to [`crate::DoubleSubmitCookieCsrfProtectedForm`] which does not
+/// throw away what the user typed when the check fails.
+///
+/// Double submit cookies expire after [`crate::CsrfConfig::expiry_seconds`], so a user who
+/// takes a while to fill in a form would otherwise get a bare 403. Instead, this guard
+/// succeeds with [`CsrfRecoverableForm::Resubmit`]: the secret fields are cleared (see
+/// [`CsrfResubmittableForm`]), a fresh token is issued through
+/// [`SetDoubleSubmitCookieCsrfToken`], and the route should render the same form again with
+/// [`CsrfResubmitForm::message`].
+///
+/// Browsers which do not send cookies can never pass the check. These are told apart from
+/// expired cookies using the token in the form, which records when its cookie expires, so
+/// they get [`CsrfViolationReason::CookiesDisabled`] and a message which asks the user to
+/// enable cookies.
+pub enum CsrfRecoverableForm<F> {
+    /// The token was valid.
+    Verified(CsrfProtectedForm<DoubleSubmitCookieCsrfToken, F>),
+    /// The token was rejected, so the form should be shown again.
+    Resubmit(CsrfResubmitForm<F>),
+}
+
+/// A form whose CSRF token was rejected, with the secret fields cleared, see
+/// [`CsrfRecoverableForm`].
+#[derive(Debug)]
+pub struct CsrfResubmitForm<F> {
+    form: F,
+    reason: CsrfViolationReason,
+    csrf_token: String,
+}
+
+impl<F> CsrfResubmitForm<F> {
+    /// Why the token was rejected.
+    pub const fn reason(&self) -> CsrfViolationReason {
+        self.reason
+    }
+
+    /// The freshly issued (and masked) token to render into the form.
+    pub fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+
+    /// A message to show the user above the form.
+    pub const fn message(&self) -> &'static str {
+        match self.reason {
+            CsrfViolationReason::CookiesDisabled => self.reason.description(),
+            CsrfViolationReason::ExpiredToken => {
+                "This form expired before it was submitted. Please check it and submit it again."
+            }
+            _ => "This form could not be verified. Please check it and submit it again.",
+        }
+    }
+
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn into_inner(self) -> F {
+        self.form
+    }
+}
+
+impl<F> Deref for CsrfResubmitForm<F> {
+    type Target = F;
+
+    fn deref(&self) -> &Self::Target {
+        &self.form
+    }
+}
+
+#[async_trait::async_trait]
+impl<'r, F> FromData<'r> for CsrfRecoverableForm<F>
+where
+    F: FromData<'r> + WithUserProvidedCsrfToken + CsrfResubmittableForm + Send + Sync,
+{
+    type Error = CsrfProtectedFormError<<F as FromData<'r>>::Error>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let verifier = request.guard::<DoubleSubmitCookieCsrfToken>().await;
+        let mut form = match F::from_data(request, data).await {
+            data::Outcome::Success(form) => form,
+            data::Outcome::Error((status, e)) => {
+                return data::Outcome::Error((status, CsrfProtectedFormError::FormParsing(e)))
+            }
+            data::Outcome::Forward(f) => return data::Outcome::Forward(f),
+        };
+        let mechanism = form.csrf_mechanism();
+        let reason = match verifier {
+            request::Outcome::Success(verifier) => match verifier.verify(&form).await {
+                Ok(proof) => {
+                    emit(request, CsrfEventKind::Verified { mechanism });
+                    set_proof_in_cache(request, proof.clone());
+                    return data::Outcome::Success(Self::Verified(CsrfProtectedForm::new(
+                        form, proof,
+                    )));
+                }
+                Err(e) => DoubleSubmitCookieCsrfToken::violation_reason(&e),
+            },
+            _ => missing_cookie_reason(request, form.csrf_token()),
+        };
+        let proof = report_only_proof::<DoubleSubmitCookieCsrfToken>(request, mechanism, reason);
+        if let Some(proof) = proof {
+            set_proof_in_cache(request, proof.clone());
+            return data::Outcome::Success(Self::Verified(CsrfProtectedForm::new(form, proof)));
+        }
+        let csrf_token = request
+            .guard::<SetDoubleSubmitCookieCsrfToken>()
+            .await
+            .succeeded()
+            .and_then(|token| token.set_masked().ok());
+        let Some(csrf_token) = csrf_token else {
+            return data::Outcome::Error((
+                Status::InternalServerError,
+                CsrfProtectedFormError::NoVerifierFound,
+            ));
+        };
+        form.clear_secret_fields();
+        data::Outcome::Success(Self::Resubmit(CsrfResubmitForm {
+            form,
+            reason,
+            csrf_token,
+        }))
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    config::CsrfConfig,
+    event::{emit, CsrfEventKind, CsrfMechanism},
+    verifier::{sealed::ReportOnlyCheck, CsrfTokenVerificationError},
+    CsrfCheckProof,
+};
+
+use rocket::{http::Method, Request};
+
+/// Why a request failed CSRF checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsrfViolationReason {
+    /// There was nothing to check the token against, e.g. no double submit cookie.
+    NoVerifier,
+    /// There was no double submit cookie, though the token says it should not have expired
+    /// yet, so the browser is most likely not sending cookies.
+    CookiesDisabled,
+    /// The request did not carry a token.
+    MissingToken,
+    /// The token did not match.
+    InvalidToken,
+    /// The token matched, but has expired.
+    ExpiredToken,
+    /// The token has already been used, see [`crate::OneTimeCsrfToken`].
+    TokenAlreadyUsed,
+    /// The token was signed with a retired key, see [`crate::CsrfKeyring`].
+    RetiredKey,
+    /// The request had no `Origin` or `Referer` header, see [`crate::CheckSameOrigin`].
+    MissingOrigin,
+    /// The request came from another origin, see [`crate::CheckSameOrigin`].
+    OriginMismatch,
+    /// The browser reported a cross site request, see [`crate::FetchMetadataPolicy`].
+    CrossSiteRequest,
+}
+
+impl CsrfViolationReason {
+    pub(crate) const ALL: [Self; 10] = [
+        Self::NoVerifier,
+        Self::CookiesDisabled,
+        Self::MissingToken,
+        Self::InvalidToken,
+        Self::ExpiredToken,
+        Self::TokenAlreadyUsed,
+        Self::RetiredKey,
+        Self::MissingOrigin,
+        Self::OriginMismatch,
+        Self::CrossSiteRequest,
+    ];
+
+    /// A stable, machine readable code for the reason, such as `invalid_token`.
+    pub const fn code(self) -> &'static str {
+        match self {
+            Self::NoVerifier => "no_verifier",
+            Self::CookiesDisabled => "cookies_disabled",
+            Self::MissingToken => "missing_token",
+            Self::InvalidToken => "invalid_token",
+            Self::ExpiredToken => "expired_token",
+            Self::TokenAlreadyUsed => "token_already_used",
+            Self::RetiredKey => "retired_key",
+            Self::MissingOrigin => "missing_origin",
+            Self::OriginMismatch => "origin_mismatch",
+            Self::CrossSiteRequest => "cross_site_request",
+        }
+    }
+
+    /// A short explanation of the reason, which can be shown to users.
+    pub const fn description(self) -> &'static str {
+        match self {
+            Self::NoVerifier => {
+                "There was nothing to check the CSRF token against. Reload the page and try again."
+            }
+            Self::CookiesDisabled => {
+                "Your browser did not send the cookie which the CSRF token is checked against. \
+                 Enable cookies for this site, reload the page and try again."
+            }
+            Self::MissingToken => "The request did not include a CSRF token.",
+            Self::InvalidToken => "The CSRF token was invalid. Reload the page and try again.",
+            Self::ExpiredToken => "The CSRF token has expired. Reload the page and try again.",
+            Self::TokenAlreadyUsed => {
+                "The CSRF token has already been used. Reload the page and try again."
+            }
+            Self::RetiredKey => {
+                "The CSRF token was signed with a key which is no longer accepted. \
+                 Reload the page and try again."
+            }
+            Self::MissingOrigin => "The request did not say which site it came from.",
+            Self::OriginMismatch | Self::CrossSiteRequest => "The request came from another site.",
+        }
+    }
+}
+
+impl std::fmt::Display for CsrfViolationReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl From<&CsrfTokenVerificationError> for CsrfViolationReason {
+    fn from(error: &CsrfTokenVerificationError) -> Self {
+        match error {
+            CsrfTokenVerificationError::Expired => Self::ExpiredToken,
+            CsrfTokenVerificationError::AlreadyUsed => Self::TokenAlreadyUsed,
+            CsrfTokenVerificationError::RetiredKey => Self::RetiredKey,
+            CsrfTokenVerificationError::CsrfTokenMismatch
+            | CsrfTokenVerificationError::Unknown(_) => Self::InvalidToken,
+        }
+    }
+}
+
+/// A request which failed CSRF checks.
+///
+/// Every violation is logged and sent to the [`crate::CsrfEventListener`]s, and the first one for a request is put in the request local
+/// cache, where it can be read with `request.local_cache(|| None::<CsrfViolation>)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsrfViolation {
+    /// The name of the route, if the request had been routed when it failed.
+    /// [`crate::CsrfFairing`] runs before routing, so its violations never have one.
+    pub route: Option<String>,
+    /// The method of the request.
+    pub method: Method,
+    /// The path of the request, without the query string.
+    pub path: String,
+    /// The `Origin` header of the request, or failing that its `Referer` header.
+    pub origin: Option<String>,
+    /// How the request was checked.
+    pub mechanism: CsrfMechanism,
+    /// Why the request failed.
+    pub reason: CsrfViolationReason,
+    /// Whether the request was let through, see [`CsrfConfig::report_only`].
+    pub report_only: bool,
+}
+
+/// Whether failed checks on the matched route should only be reported.
+pub(crate) fn route_is_report_only(request: &Request<'_>) -> bool {
+    let config = CsrfConfig::from_request(request);
+    config.report_only
+        || request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .is_some_and(|name| config.report_only_routes.iter().any(|n| n == name))
+}
+
+/// Records a failed check. If `report_only` is set and there is a `proof` to hand out, the
+/// request is let through and the proof is returned, otherwise the check must be enforced.
+pub(crate) fn excuse_violation<P>(
+    request: &Request<'_>,
+    mechanism: CsrfMechanism,
+    reason: CsrfViolationReason,
+    report_only: bool,
+    proof: Option<P>,
+) -> Option<P> {
+    let report_only = report_only && proof.is_some();
+    let headers = request.headers();
+    let violation = CsrfViolation {
+        route: request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .map(str::to_owned),
+        method: request.method(),
+        path: request.uri().path().to_string(),
+        origin: headers
+            .get_one("Origin")
+            .or_else(|| headers.get_one("Referer"))
+            .map(str::to_owned),
+        mechanism,
+        reason,
+        report_only,
+    };
+    log::warn!(
+        "CSRF violation ({}): {} {} route={} origin={} mechanism={} reason={}",
+        if report_only {
+            "report-only"
+        } else {
+            "blocked"
+        },
+        violation.method,
+        violation.path,
+        violation.route.as_deref().unwrap_or("-"),
+        violation.origin.as_deref().unwrap_or("-"),
+        violation.mechanism,
+        violation.reason,
+    );
+    emit(
+        request,
+        CsrfEventKind::Failed {
+            mechanism,
+            reason,
+            report_only,
+        },
+    );
+    request.local_cache(|| Some(violation));
+    if report_only {
+        proof
+    } else {
+        None
+    }
+}
+
+/// Records a failed check which is always enforced.
+pub(crate) fn enforce_violation(
+    request: &Request<'_>,
+    mechanism: CsrfMechanism,
+    reason: CsrfViolationReason,
+) {
+    excuse_violation::<CsrfCheckProof>(request, mechanism, reason, false, None);
+}
+
+/// Records a failed check by a guard, returning the verifier's report-only proof if the
+/// request should be let through.
+pub(crate) fn report_only_proof<V: crate::CsrfTokenVerifier>(
+    request: &Request<'_>,
+    mechanism: CsrfMechanism,
+    reason: CsrfViolationReason,
+) -> Option<V::Proof> {
+    excuse_violation(
+        request,
+        mechanism,
+        reason,
+        route_is_report_only(request),
+        V::report_only_proof(ReportOnlyCheck { mechanism }),
+    )
+}
+
+/// Like [`report_only_proof`], for guards which hand out a [`CsrfCheckProof`] from `V`
+/// themselves.
+pub(crate) fn report_only_check_proof<V: ?Sized>(
+    request: &Request<'_>,
+    mechanism: CsrfMechanism,
+    reason: CsrfViolationReason,
+) -> Option<CsrfCheckProof> {
+    excuse_violation(
+        request,
+        mechanism,
+        reason,
+        route_is_report_only(request),
+        Some(CsrfCheckProof::excused::<V>(mechanism)),
+    )
+}
@@ -0,0 +1,254 @@
+use crate::{
+    fetch_metadata::FetchMetadataPolicyError,
+    form::{CsrfProtectedFormError, CsrfProtectedFormWithGuardError},
+    header::CheckCsrfProtectionHeaderError,
+    origin::CheckSameOriginError,
+    preverified::CsrfPreverifiedFormError,
+    report::{CsrfViolation, CsrfViolationReason},
+};
+
+use rocket::{
+    catcher::{self, Catcher},
+    http::{ContentType, Status},
+    response::{self, Responder},
+    serde::json::serde_json::json,
+    Request,
+};
+
+/// Code for a 403 caught by [`CsrfErrorResponse::catcher`] which was not a CSRF failure.
+const FORBIDDEN_CODE: &str = "forbidden";
+
+/// A response explaining why a request was rejected, with a stable machine readable code
+/// (see [`CsrfViolationReason::code`]).
+///
+/// The body is negotiated from the `Accept` header: clients which prefer
+/// `application/problem+json` or `application/json` get an
+/// [RFC 7807](https://www.rfc-editor.org/rfc/rfc7807) problem details object, with the code
+/// in its `reason` member, while everyone else gets a small HTML page.
+///
+/// ```json
+/// {
+///   "type": "about:blank",
+///   "title": "Forbidden",
+///   "status": 403,
+///   "detail": "The CSRF token has expired. Reload the page and try again.",
+///   "reason": "expired_token"
+/// }
+/// ```
+///
+/// The error types of this crate's guards respond with this, so routes can take
+/// e.g. `Result<CheckCsrfProtectionHeader<V>, CheckCsrfProtectionHeaderError>` and return the
+/// error as is. For everything else, register [`CsrfErrorResponse::catcher`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsrfErrorResponse {
+    status: Status,
+    code: &'static str,
+    detail: &'static str,
+}
+
+impl CsrfErrorResponse {
+    const fn new(status: Status, code: &'static str, detail: &'static str) -> Self {
+        Self {
+            status,
+            code,
+            detail,
+        }
+    }
+
+    /// A 403 catcher which responds with the reason the request failed CSRF checks, as read
+    /// from the [`CsrfViolation`] in the request local cache. Other 403s get a generic
+    /// response with the code `forbidden`.
+    ///
+    /// ```rust,no_run
+    /// # use rocket_csrf_guard::CsrfErrorResponse;
+    /// let rocket = rocket::build().register("/", vec![CsrfErrorResponse::catcher()]);
+    /// ```
+    pub fn catcher() -> Catcher {
+        Catcher::new(403, handle_forbidden)
+    }
+
+    /// The status of the response.
+    pub const fn status(&self) -> Status {
+        self.status
+    }
+
+    /// The machine readable code of the response, such as `invalid_token`.
+    pub const fn code(&self) -> &'static str {
+        self.code
+    }
+
+    /// A human readable explanation of the code.
+    pub const fn detail(&self) -> &'static str {
+        self.detail
+    }
+
+    /// Responds with the reason cached for the request if there is one, since the guard which
+    /// reported it knows more than its error type does, or with `reason` otherwise.
+    fn for_violation(request: &Request<'_>, reason: CsrfViolationReason) -> Self {
+        request
+            .local_cache(|| None::<CsrfViolation>)
+            .as_ref()
+            .map_or(reason, |violation| violation.reason)
+            .into()
+    }
+
+    /// The response for a request body which could not be parsed.
+    const fn invalid_body() -> Self {
+        Self::new(
+            Status::BadRequest,
+            "invalid_body",
+            "The request body could not be parsed.",
+        )
+    }
+}
+
+impl From<CsrfViolationReason> for CsrfErrorResponse {
+    fn from(reason: CsrfViolationReason) -> Self {
+        Self::new(Status::Forbidden, reason.code(), reason.description())
+    }
+}
+
+/// Whether the client would rather have a problem details object than an HTML page.
+fn prefers_json(request: &Request<'_>) -> bool {
+    request.accept().is_some_and(|accept| {
+        let media_type = accept.preferred().media_type();
+        media_type.top() == "application"
+            && (media_type.sub() == "json" || media_type.sub() == "problem+json")
+    })
+}
+
+impl<'r> Responder<'r, 'static> for CsrfErrorResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let title = self.status.reason().unwrap_or("Error");
+        let (content_type, body) = if prefers_json(request) {
+            let problem = json!({
+                "type": "about:blank",
+                "title": title,
+                "status": self.status.code,
+                "detail": self.detail,
+                "reason": self.code,
+            });
+            (
+                ContentType::new("application", "problem+json"),
+                problem.to_string(),
+            )
+        } else {
+            let page = format!(
+                "<!DOCTYPE html>\n\
+                 <html lang=\"en\">\n\
+                 <head><meta charset=\"utf-8\"><title>{status} {title}</title></head>\n\
+                 <body>\n\
+                 <h1>{status} {title}</h1>\n\
+                 <p>{detail}</p>\n\
+                 <p><small>Reason: <code>{code}</code></small></p>\n\
+                 </body>\n\
+                 </html>\n",
+                status = self.status.code,
+                detail = self.detail,
+                code = self.code,
+            );
+            (ContentType::HTML, page)
+        };
+        (self.status, (content_type, body)).respond_to(request)
+    }
+}
+
+fn handle_forbidden<'r>(status: Status, request: &'r Request<'_>) -> catcher::BoxFuture<'r> {
+    let response = request
+        .local_cache(|| None::<CsrfViolation>)
+        .as_ref()
+        .map_or(
+            CsrfErrorResponse::new(
+                status,
+                FORBIDDEN_CODE,
+                "You are not allowed to make this request.",
+            ),
+            |violation| violation.reason.into(),
+        );
+    Box::pin(async move { response.respond_to(request) })
+}
+
+impl<'r> Responder<'r, 'static> for CheckCsrfProtectionHeaderError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let reason = match self {
+            Self::NoVerifierFound => CsrfViolationReason::NoVerifier,
+            Self::NoHeaderPresent => CsrfViolationReason::MissingToken,
+            Self::CsrfTokenVerificationError => CsrfViolationReason::InvalidToken,
+        };
+        CsrfErrorResponse::for_violation(request, reason).respond_to(request)
+    }
+}
+
+impl<'r, T> Responder<'r, 'static> for CsrfProtectedFormError<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let response = match self {
+            Self::NoVerifierFound => {
+                CsrfErrorResponse::for_violation(request, CsrfViolationReason::NoVerifier)
+            }
+            Self::CsrfTokenVerificationError => {
+                CsrfErrorResponse::for_violation(request, CsrfViolationReason::InvalidToken)
+            }
+            Self::FormParsing(_) => CsrfErrorResponse::invalid_body(),
+        };
+        response.respond_to(request)
+    }
+}
+
+/// Failures of the inner request guard `G` are not CSRF failures, so they only get a
+/// generic response with the guard's status.
+impl<'r, T, E> Responder<'r, 'static> for CsrfProtectedFormWithGuardError<T, E> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let response = match self {
+            Self::CsrfProtection(error) => return error.respond_to(request),
+            Self::FromRequestForwarded => CsrfErrorResponse::new(
+                Status::InternalServerError,
+                "request_guard_forwarded",
+                "The request could not be processed.",
+            ),
+            Self::FromRequestFailed(status, _) => CsrfErrorResponse::new(
+                status,
+                "request_guard_failed",
+                "The request could not be processed.",
+            ),
+        };
+        response.respond_to(request)
+    }
+}
+
+impl<'r, T> Responder<'r, 'static> for CsrfPreverifiedFormError<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let response = match self {
+            Self::NoVerifierFound => {
+                CsrfErrorResponse::for_violation(request, CsrfViolationReason::NoVerifier)
+            }
+            Self::NoCsrfTokenFound => {
+                CsrfErrorResponse::for_violation(request, CsrfViolationReason::MissingToken)
+            }
+            Self::CsrfTokenVerificationError => {
+                CsrfErrorResponse::for_violation(request, CsrfViolationReason::InvalidToken)
+            }
+            Self::FormParsing(_) => CsrfErrorResponse::invalid_body(),
+        };
+        response.respond_to(request)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for CheckSameOriginError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let reason = match self {
+            Self::NoOriginPresent => CsrfViolationReason::MissingOrigin,
+            Self::OriginMismatch => CsrfViolationReason::OriginMismatch,
+        };
+        CsrfErrorResponse::for_violation(request, reason).respond_to(request)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for FetchMetadataPolicyError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let reason = match self {
+            Self::CrossSiteRequest => CsrfViolationReason::CrossSiteRequest,
+            Self::CsrfTokenCheckFailed => CsrfViolationReason::InvalidToken,
+        };
+        CsrfErrorResponse::for_violation(request, reason).respond_to(request)
+    }
+}
@@ -0,0 +1,102 @@
+use crate::util::{mask_token, now_unix_seconds, random_id};
+
+use serde::{ser::Error as _, Serialize, Serializer};
+
+/// Default number of seconds a rotated out token keeps working for, see
+/// [`crate::CsrfConfig::rotation_grace_seconds`].
+pub const CSRF_TOKEN_ROTATION_GRACE_SECONDS: i64 = 30;
+
+/// A session's CSRF token, which can be replaced when the session's privileges change.
+///
+/// Store this in your session, and call [`RotatingCsrfToken::rotate`] whenever the user
+/// logs in, elevates their privileges, or otherwise changes what the session can do, so a
+/// token leaked beforehand stops working. The previous token keeps being accepted for a
+/// short grace window, so requests which were already in flight do not fail.
+///
+/// Implement [`crate::VerifierWithKnownExpectedToken`] for your session by returning
+/// [`RotatingCsrfToken::current`] as the expected token and [`RotatingCsrfToken::previous`]
+/// as the previous one.
+///
+/// Serializing it produces a freshly masked copy of the current token, so it can be passed
+/// to templates as is.
+#[derive(Clone, Debug)]
+pub struct RotatingCsrfToken {
+    current: String,
+    issued_at: i64,
+    previous: Option<(String, i64)>,
+}
+
+impl RotatingCsrfToken {
+    /// Generates a new token of `token_length` random bytes, which should usually be
+    /// [`crate::CsrfConfig::token_length`].
+    pub fn new(token_length: usize) -> Result<Self, rand::Error> {
+        Ok(Self {
+            current: random_id(token_length)?,
+            issued_at: now_unix_seconds(),
+            previous: None,
+        })
+    }
+
+    /// Restores a token from storage, using the values returned by [`Self::current`],
+    /// [`Self::issued_at`] and [`Self::previous_until`].
+    pub fn from_parts(current: String, issued_at: i64, previous: Option<(String, i64)>) -> Self {
+        Self {
+            current,
+            issued_at,
+            previous,
+        }
+    }
+
+    /// The current token.
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    /// When the current token was issued, in seconds since the unix epoch.
+    pub const fn issued_at(&self) -> i64 {
+        self.issued_at
+    }
+
+    /// The token which was rotated out, if it is still within its grace window.
+    pub fn previous(&self) -> Option<&str> {
+        self.previous
+            .as_ref()
+            .filter(|(_, valid_until)| now_unix_seconds() < *valid_until)
+            .map(|(token, _)| token.as_str())
+    }
+
+    /// The token which was rotated out and when its grace window ends, even if it has ended.
+    pub fn previous_until(&self) -> Option<(&str, i64)> {
+        self.previous
+            .as_ref()
+            .map(|(token, valid_until)| (token.as_str(), *valid_until))
+    }
+
+    /// Replaces the current token with a new one of `token_length` random bytes, which is
+    /// returned. The old token keeps working for `grace_seconds` (not at all if it is zero).
+    /// These should usually be [`crate::CsrfConfig::token_length`] and
+    /// [`crate::CsrfConfig::rotation_grace_seconds`].
+    pub fn rotate(&mut self, token_length: usize, grace_seconds: i64) -> Result<&str, rand::Error> {
+        let token = random_id(token_length)?;
+        let now = now_unix_seconds();
+        let previous = std::mem::replace(&mut self.current, token);
+        self.previous = Some((previous, now.saturating_add(grace_seconds)));
+        self.issued_at = now;
+        Ok(&self.current)
+    }
+
+    /// Returns a freshly masked copy of the current token.
+    pub fn masked(&self) -> Result<String, rand::Error> {
+        mask_token(&self.current)
+    }
+}
+
+/// Serializes the masked current token, for use in forms.
+impl Serialize for RotatingCsrfToken {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.masked().map_err(S::Error::custom)?)
+    }
+}
@@ -0,0 +1,122 @@
+use crate::verifier::VerifierWithKnownExpectedToken;
+
+use hmac::{Hmac, Mac};
+use rocket::{
+    http::{Method, Status},
+    request::{FromRequest, Outcome, Request},
+};
+use sha2::Sha256;
+
+/// The action a request performs, e.g. `POST /logout`, which an [`ActionScopedCsrfToken`]
+/// is bound to. `route_uri` is the URI of the route as declared, including its mount point
+/// and any dynamic segments (e.g. `/users/<id>/delete`).
+pub fn csrf_action_scope(method: Method, route_uri: &str) -> String {
+    format!("{method} {route_uri}")
+}
+
+/// Derives the token for an action from the session's token.
+pub(crate) fn action_scoped_token(expected_token: &str, scope: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(expected_token.as_bytes())
+        .expect("HMAC accepts any key length");
+    mac.update(scope.as_bytes());
+    base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+}
+
+/// Determines which action an [`ActionScopedCsrfToken`] is checked against.
+pub trait CsrfActionScope {
+    /// The scope of the current request, see [`csrf_action_scope`].
+    /// Returning `None` fails the check.
+    fn action_scope(request: &Request<'_>) -> Option<String>;
+}
+
+/// Scopes tokens to the method and URI of the route handling the request.
+///
+/// Only available once a route has been matched, so this does not work with
+/// [`crate::CsrfFairing`], which runs before routing.
+#[derive(Debug)]
+pub struct MatchedRoute;
+
+impl CsrfActionScope for MatchedRoute {
+    fn action_scope(request: &Request<'_>) -> Option<String> {
+        request
+            .route()
+            .map(|route| csrf_action_scope(route.method, route.uri.as_str()))
+    }
+}
+
+/// Wraps a [`VerifierWithKnownExpectedToken`] so that its tokens only work for one action.
+///
+/// Instead of the session's token itself, forms and requests must carry
+/// `HMAC(expected_token, "METHOD /route")`, which can be generated with
+/// [`VerifierWithKnownExpectedToken::masked_action_scoped_token`]. A token leaked from one
+/// form then cannot be used against any other endpoint.
+///
+/// The action is determined by `A`, which defaults to the matched route (see [`MatchedRoute`]).
+/// Implement [`CsrfActionScope`] to share one scope between several routes, or to scope
+/// tokens more finely.
+pub struct ActionScopedCsrfToken<V, A = MatchedRoute> {
+    inner: V,
+    scoped_token: String,
+    scoped_previous_token: Option<String>,
+    _marker: std::marker::PhantomData<fn() -> A>,
+}
+
+impl<V, A> ActionScopedCsrfToken<V, A> {
+    /// The wrapped verifier.
+    pub const fn inner(&self) -> &V {
+        &self.inner
+    }
+}
+
+impl<V, A> VerifierWithKnownExpectedToken for ActionScopedCsrfToken<V, A>
+where
+    V: VerifierWithKnownExpectedToken,
+{
+    type Proof = V::Proof;
+
+    fn expected_token(&self) -> &str {
+        &self.scoped_token
+    }
+
+    fn previous_expected_token(&self) -> Option<&str> {
+        self.scoped_previous_token.as_deref()
+    }
+
+    fn issued_at(&self) -> Option<i64> {
+        self.inner.issued_at()
+    }
+
+    fn max_age_seconds(&self) -> Option<i64> {
+        self.inner.max_age_seconds()
+    }
+}
+
+/// Fetches the wrapped verifier and the scope of the current request.
+#[async_trait::async_trait]
+impl<'r, V, A> FromRequest<'r> for ActionScopedCsrfToken<V, A>
+where
+    V: VerifierWithKnownExpectedToken + FromRequest<'r>,
+    A: CsrfActionScope,
+{
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let inner = match request.guard::<V>().await {
+            Outcome::Success(inner) => inner,
+            Outcome::Error((status, _)) | Outcome::Forward(status) => {
+                return Outcome::Forward(status)
+            }
+        };
+        let Some(scope) = A::action_scope(request) else {
+            return Outcome::Forward(Status::InternalServerError);
+        };
+        Outcome::Success(Self {
+            scoped_token: action_scoped_token(inner.expected_token(), &scope),
+            scoped_previous_token: inner
+                .previous_expected_token()
+                .map(|previous| action_scoped_token(previous, &scope)),
+            inner,
+            _marker: std::marker::PhantomData,
+        })
+    }
+}
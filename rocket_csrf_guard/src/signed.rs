@@ -0,0 +1,281 @@
+use crate::{
+    config::CsrfConfig,
+    event::{CsrfEventEmitter, CsrfEventKind},
+    keyring::{CsrfKeyring, CsrfKeyringError, SIGNING_KEYS_CONFIG_KEY},
+    report::CsrfViolationReason,
+    util::random_id,
+    verifier::sealed::ReportOnlyCheck,
+    CsrfCheckProof, CsrfTokenVerificationError, CsrfTokenVerifier, WithUserProvidedCsrfToken,
+};
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    http::Status,
+    request::{FromRequest, Outcome, Request},
+    serde::Deserialize,
+    Build, Rocket, State,
+};
+use serde::{ser::Error as _, Serialize, Serializer};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Default maximum age of a [`SignedCsrfToken`].
+pub const SIGNED_CSRF_TOKEN_MAX_AGE_SECONDS: i64 = 3600;
+
+/// Domain separation for deriving the signing key, so it is never the same as the key
+/// Rocket uses for private cookies.
+const SIGNING_KEY_CONTEXT: &[u8] = b"rocket_csrf_guard signed csrf token key";
+
+/// Domain separation for tokens which are not bound to a session, so they can never be
+/// confused with a [`SignedCsrfToken`].
+const UNBOUND_TOKEN_CONTEXT: &[u8] = b"rocket_csrf_guard unbound csrf token";
+
+/// A request guard which identifies the session a [`SignedCsrfToken`] is bound to.
+///
+/// Implement this on your session type (which must also implement [`FromRequest`]) so that
+/// a token minted for one session cannot be used with another.
+pub trait CsrfSessionBinding {
+    /// A stable identifier for the session.
+    /// This does not need to be secret (a hash of the session ID works well), but it must
+    /// be unique per session.
+    fn csrf_session_id(&self) -> &str;
+
+    /// How long tokens bound to this session are valid for after being issued, in seconds.
+    fn csrf_token_max_age_seconds(&self) -> i64 {
+        SIGNED_CSRF_TOKEN_MAX_AGE_SECONDS
+    }
+}
+
+/// A key used to sign [`SignedCsrfToken`]s, one of the keys in a [`CsrfKeyring`].
+///
+/// Unless keys are configured, a single key is derived from Rocket's `secret_key` when one is
+/// configured, so tokens survive restarts. Otherwise a random key is generated at launch,
+/// matching what Rocket does for private cookies.
+/// Attach [`CsrfSigningKey::fairing`] to make the keys available to request guards.
+#[derive(Clone)]
+pub struct CsrfSigningKey([u8; 32]);
+
+/// The fewest bytes of key material accepted in `secret_key`, matching Rocket.
+const MIN_SECRET_KEY_BYTES: usize = 32;
+
+/// The raw `secret_key` as it appears in the configuration.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum SecretKeyMaterial {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl CsrfSigningKey {
+    /// A fairing which loads the [`CsrfKeyring`] and adds it to managed state.
+    /// Launch fails if `csrf.signing_keys` is present but invalid.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::try_on_ignite("CSRF signing keys", |rocket| async move {
+            match CsrfKeyring::from_rocket(&rocket) {
+                Ok(keyring) => Ok(rocket.manage(keyring)),
+                Err(e) => {
+                    log::error!("invalid `{SIGNING_KEYS_CONFIG_KEY}` configuration: {e}");
+                    Err(rocket)
+                }
+            }
+        })
+    }
+
+    /// Derives the key from Rocket's `secret_key`, decoded the same way Rocket does: strings
+    /// are base64 (44 or 88 characters) or hex (64 characters), and at least
+    /// [`MIN_SECRET_KEY_BYTES`] of key material are required.
+    pub(crate) fn from_rocket(rocket: &Rocket<Build>) -> Result<Self, CsrfKeyringError> {
+        let material = match rocket
+            .figment()
+            .extract_inner::<SecretKeyMaterial>("secret_key")
+        {
+            Ok(SecretKeyMaterial::Text(text)) => {
+                Some(decode_secret(&text).ok_or(CsrfKeyringError::InvalidSecretKey)?)
+            }
+            Ok(SecretKeyMaterial::Bytes(bytes)) => Some(bytes),
+            Err(_) => None,
+        };
+        let material = match material {
+            Some(material) if material.len() < MIN_SECRET_KEY_BYTES => {
+                return Err(CsrfKeyringError::InvalidSecretKey)
+            }
+            Some(material) if material.iter().any(|b| *b != 0) => material,
+            // No usable key, so behave like Rocket and use a random one.
+            _ => {
+                let mut buf = vec![0; MIN_SECRET_KEY_BYTES];
+                rand::thread_rng().try_fill_bytes(&mut buf)?;
+                buf
+            }
+        };
+        Ok(Self::derive(&material))
+    }
+
+    /// Derives a key from a secret configured for a [`CsrfKeyring`], which is decoded like
+    /// `secret_key`. Returns `None` if it cannot be decoded or is too short.
+    pub(crate) fn from_secret(secret: &str) -> Option<Self> {
+        decode_secret(secret)
+            .filter(|material| material.len() >= MIN_SECRET_KEY_BYTES)
+            .map(|material| Self::derive(&material))
+    }
+
+    fn derive(material: &[u8]) -> Self {
+        let mut mac = HmacSha256::new_from_slice(material).expect("HMAC accepts any key length");
+        mac.update(SIGNING_KEY_CONTEXT);
+        Self(mac.finalize().into_bytes().into())
+    }
+
+    pub(crate) fn sign(&self, session_id: &str, issued_at: i64, nonce: &str) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC accepts any key length");
+        // Length prefix the session ID so the fields cannot be shifted into each other.
+        mac.update(&(session_id.len() as u64).to_be_bytes());
+        mac.update(session_id.as_bytes());
+        mac.update(&issued_at.to_be_bytes());
+        mac.update(nonce.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Signs a token which is not bound to any session, see [`crate::XsrfCookieCsrfToken`].
+    pub(crate) fn sign_unbound(&self, issued_at: i64, nonce: &str) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC accepts any key length");
+        mac.update(UNBOUND_TOKEN_CONTEXT);
+        mac.update(&issued_at.to_be_bytes());
+        mac.update(nonce.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+/// Decodes a secret the same way Rocket decodes `secret_key`: base64 if it is 44 or 88
+/// characters long, hex if it is 64 characters long.
+fn decode_secret(text: &str) -> Option<Vec<u8>> {
+    match text.len() {
+        44 | 88 => base64::decode(text).ok(),
+        64 => hex::decode(text).ok(),
+        _ => None,
+    }
+}
+
+impl std::fmt::Debug for CsrfSigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CsrfSigningKey([redacted])")
+    }
+}
+
+/// Stateless CSRF protection using signed tokens.
+///
+/// Tokens are `HMAC(key, session_id || issued_at || nonce)`, keyed by the primary key of the
+/// [`CsrfKeyring`], so they can be verified without storing anything per session. Tokens
+/// signed by a key which has since been retired are rejected, as are tokens older than
+/// [`CsrfSessionBinding::csrf_token_max_age_seconds`].
+/// The session is provided by `S`, see [`CsrfSessionBinding`].
+///
+/// Use this as a request guard to issue tokens (it implements [`serde::Serialize`],
+/// minting a fresh token every time), and as the verifier for [`crate::CsrfProtectedForm`]
+/// or [`crate::CheckCsrfProtectionHeader`] to check them.
+pub struct SignedCsrfToken<S> {
+    keyring: CsrfKeyring,
+    session_id: String,
+    max_age: i64,
+    token_length: usize,
+    events: CsrfEventEmitter,
+    _marker: std::marker::PhantomData<fn() -> S>,
+}
+
+impl<S> SignedCsrfToken<S> {
+    /// Mints a new token for the current session.
+    pub fn issue(&self) -> Result<String, rand::Error> {
+        let nonce = random_id(self.token_length)?;
+        let token = self.keyring.issue(&nonce, |key, issued_at, nonce| {
+            key.sign(&self.session_id, issued_at, nonce)
+        });
+        self.events.emit(CsrfEventKind::TokenIssued {
+            verifier: "SignedCsrfToken",
+        });
+        Ok(token)
+    }
+
+    /// Verifies a token like [`CsrfTokenVerifier::verify`], returning the ID of the key in
+    /// the [`CsrfKeyring`] which signed it.
+    pub fn verify_key_id(&self, token: &str) -> Result<&str, CsrfTokenVerificationError> {
+        self.keyring
+            .verify(token, self.max_age, |key, issued_at, nonce| {
+                key.sign(&self.session_id, issued_at, nonce)
+            })
+    }
+}
+
+impl<S> std::fmt::Debug for SignedCsrfToken<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignedCsrfToken")
+            .field("session_id", &self.session_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Serializes a freshly minted token, for use in forms.
+impl<S> Serialize for SignedCsrfToken<S> {
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+    where
+        Ser: Serializer,
+    {
+        serializer.serialize_str(&self.issue().map_err(Ser::Error::custom)?)
+    }
+}
+
+/// Verifies that the received token was signed for the current session.
+#[async_trait::async_trait]
+impl<S> CsrfTokenVerifier for SignedCsrfToken<S> {
+    type Proof = CsrfCheckProof;
+    type Error = CsrfTokenVerificationError;
+
+    async fn verify(
+        &self,
+        token: &(dyn WithUserProvidedCsrfToken + Send + Sync),
+    ) -> Result<Self::Proof, Self::Error> {
+        let key_id = self.verify_key_id(token.csrf_token())?;
+        log::debug!("CSRF token was signed with key `{key_id}`");
+        Ok(CsrfCheckProof::new::<Self>(token.csrf_mechanism()))
+    }
+
+    fn violation_reason(error: &Self::Error) -> CsrfViolationReason {
+        error.into()
+    }
+
+    fn report_only_proof(check: ReportOnlyCheck) -> Option<Self::Proof> {
+        Some(CsrfCheckProof::excused::<Self>(check.mechanism))
+    }
+}
+
+/// Fetches the session binding and signing keys for the current request.
+#[async_trait::async_trait]
+impl<'r, S> FromRequest<'r> for SignedCsrfToken<S>
+where
+    S: CsrfSessionBinding + FromRequest<'r>,
+{
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let (session_id, max_age) = match request.guard::<S>().await {
+            Outcome::Success(session) => (
+                session.csrf_session_id().to_owned(),
+                session.csrf_token_max_age_seconds(),
+            ),
+            Outcome::Error((status, _)) | Outcome::Forward(status) => {
+                return Outcome::Forward(status)
+            }
+        };
+        let Some(keyring) = request.guard::<&State<CsrfKeyring>>().await.succeeded() else {
+            return Outcome::Forward(Status::InternalServerError);
+        };
+        Outcome::Success(Self {
+            keyring: keyring.inner().clone(),
+            session_id,
+            max_age,
+            token_length: CsrfConfig::from_request(request).token_length,
+            events: CsrfEventEmitter::for_request(request),
+            _marker: std::marker::PhantomData,
+        })
+    }
+}
@@ -1,18 +1,50 @@
-use super::example_app::build_rocket;
+use super::{
+    csrf_action_scope,
+    example_app::build_rocket,
+    scoped::action_scoped_token,
+    util::{constant_time_eq, unmask_token},
+    CheckCsrfProtectionHeader, CheckCsrfProtectionHeaderError, CheckSameOrigin, CsrfAudit,
+    CsrfAuditReport, CsrfCheckProof, CsrfConfig, CsrfEvent, CsrfEventKind, CsrfEventListener,
+    CsrfFairing, CsrfKeyring, CsrfMechanism, CsrfMetrics, CsrfPreverifiedForm, CsrfProtectedForm,
+    CsrfProtectedFormWithGuard, CsrfProtectedMultipartForm, CsrfRecoverableForm,
+    CsrfResubmittableForm, CsrfSigningKey, CsrfTokenVerificationError, CsrfTokenVerifier,
+    CsrfViolation, CsrfViolationReason, DoubleSubmitCookieCsrfToken, FetchMetadataPolicy,
+    InMemoryNonceStore, ManuallySourcedCsrfToken_DO_NOT_USE_UNLESS_YOU_ARE_SURE,
+    NavigationalFetchMetadataPolicy, NonceStatus, NonceStore, RotatingCsrfToken,
+    RouteCsrfProtection, SetDoubleSubmitCookieCsrfToken, SetXsrfCookieCsrfToken,
+    VerifierWithKnownExpectedToken, WithUserProvidedCsrfToken, XsrfCookieCsrfToken,
+    DEFAULT_SIGNING_KEY_ID,
+};
 
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use console::Style;
 use rocket::{
-    http::{ContentType, Header, Status},
-    local::blocking::Client,
+    data::{self, Data, FromData},
+    delete,
+    form::{Form, FromForm},
+    fs::TempFile,
+    get,
+    http::{uri::Host, ContentType, Cookie, Header, Method, Status},
+    local::blocking::{Client, LocalResponse},
+    patch, post, put,
+    request::{self, FromRequest},
+    routes,
+    serde::json::{serde_json, Value},
+    Build, Request, Rocket,
 };
 use similar::{ChangeTag, TextDiff};
 
 macro_rules! fetch_login_page {
-    () => {{
-        let client = Client::tracked(build_rocket()).unwrap();
+    () => {
+        fetch_login_page!(build_rocket())
+    };
+    ($rocket:expr) => {{
+        let client = Client::tracked($rocket).unwrap();
         let (cookie, csrf_token) = {
             let response = client.get("/").dispatch();
             assert_eq!(response.status(), Status::Ok);
@@ -224,6 +256,2385 @@ fn test_session_based_tokens_work() {
     assert!(!text.unwrap().contains("passed the right csrf token"));
 }
 
+#[test]
+fn test_json_body_works_with_session_token() {
+    let (client, _, csrf_token) = fetch_login_page!();
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(format!("name=Hasnain&csrf_token={csrf_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::SeeOther);
+    let session_csrf_token =
+        rendered_csrf_token(&client.get("/").dispatch().into_string().unwrap());
+
+    let response = client
+        .post("/rename")
+        .header(ContentType::JSON)
+        .body(format!(
+            r#"{{"name": "Lakhani", "csrf_token": "{session_csrf_token}"}}"#
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_string().unwrap(),
+        "Hasnain would now be known as Lakhani"
+    );
+}
+
+#[test]
+fn test_json_body_fails_with_incorrect_or_missing_token() {
+    let (client, _, csrf_token) = fetch_login_page!();
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(format!("name=Hasnain&csrf_token={csrf_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::SeeOther);
+
+    // The double submit token is not the session's token.
+    let response = client
+        .post("/rename")
+        .header(ContentType::JSON)
+        .body(format!(
+            r#"{{"name": "Lakhani", "csrf_token": "{csrf_token}"}}"#
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    // No token at all fails to parse.
+    let response = client
+        .post("/rename")
+        .header(ContentType::JSON)
+        .body(r#"{"name": "Lakhani"}"#)
+        .dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+}
+
+#[test]
+fn test_constant_time_eq() {
+    assert!(constant_time_eq(b"some_token", b"some_token"));
+    assert!(constant_time_eq(b"", b""));
+    // Different lengths never match, even when one is a prefix of the other
+    assert!(!constant_time_eq(b"some_token", b"some_tok"));
+    assert!(!constant_time_eq(b"some_tok", b"some_token"));
+    assert!(!constant_time_eq(b"", b"some_token"));
+    // Differing only in the last byte
+    assert!(!constant_time_eq(b"some_token", b"some_tokeN"));
+}
+
+#[test]
+fn test_login_fails_with_near_miss_csrf_tokens() {
+    let (client, _, csrf_token) = fetch_login_page!();
+
+    // Same length, only the last byte differs
+    let mut last_byte_differs = csrf_token.clone();
+    let last = last_byte_differs.pop().unwrap();
+    last_byte_differs.push(if last == 'A' { 'B' } else { 'A' });
+
+    // A strict prefix of the right token
+    let truncated = &csrf_token[..csrf_token.len() - 1];
+
+    // And the right token with something extra on the end
+    let extended = format!("{csrf_token}A");
+
+    for wrong_token in [last_byte_differs.as_str(), truncated, extended.as_str()] {
+        let response = client
+            .post("/")
+            .header(ContentType::Form)
+            .body(format!("name=Hasnain&csrf_token={wrong_token}"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
+
+/// Extracts the first csrf token rendered into a form on the page.
+fn rendered_csrf_token(text: &str) -> String {
+    let marker = "name=\"csrf_token\" value=\"";
+    let start = text.find(marker).unwrap() + marker.len();
+    let end = start + text[start..].find('"').unwrap();
+    text[start..end].to_string()
+}
+
+#[test]
+fn test_rendered_double_submit_token_is_masked() {
+    let client = Client::tracked(build_rocket()).unwrap();
+    let response = client.get("/").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let cookie_token = response
+        .cookies()
+        .get_private("__Host-csrf-token")
+        .unwrap()
+        .value()
+        .to_owned();
+    let rendered_token = rendered_csrf_token(&response.into_string().unwrap());
+
+    // The raw token never makes it into the page
+    assert_ne!(rendered_token, cookie_token);
+    assert!(!rendered_token.contains(&cookie_token));
+
+    // But the masked version still verifies
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(format!("name=Hasnain&csrf_token={rendered_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::SeeOther);
+}
+
+#[test]
+fn test_rendered_session_token_is_masked_differently_each_time() {
+    let (client, _, csrf_token) = fetch_login_page!();
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(format!("name=Hasnain&csrf_token={csrf_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::SeeOther);
+
+    let first = rendered_csrf_token(&client.get("/").dispatch().into_string().unwrap());
+    let second = rendered_csrf_token(&client.get("/").dispatch().into_string().unwrap());
+    assert_ne!(first, second);
+
+    // Both renders are valid for the same session
+    for token in [&first, &second] {
+        let response = client
+            .get("/header")
+            .header(Header::new("X-Csrf-Token", token.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
+
+macro_rules! logged_in_client {
+    () => {
+        logged_in_client!(build_rocket())
+    };
+    ($rocket:expr) => {{
+        let (client, _, csrf_token) = fetch_login_page!($rocket);
+        {
+            let response = client
+                .post("/")
+                .header(ContentType::Form)
+                .body(format!("name=Hasnain&csrf_token={csrf_token}"))
+                .dispatch();
+            assert_eq!(response.status(), Status::SeeOther);
+        }
+        client
+    }};
+}
+
+/// Elevates the session's privileges, returning the old and new csrf tokens.
+fn elevate_session(client: &Client) -> (String, String) {
+    let text = client.get("/").dispatch().into_string().unwrap();
+    let old_token = rendered_csrf_token(&text);
+    let response = client
+        .post("/elevate")
+        .header(Header::new("X-Csrf-Token", old_token.clone()))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    (old_token, response.into_string().unwrap())
+}
+
+fn passes_header_check(client: &Client, csrf_token: &str) -> bool {
+    let response = client
+        .get("/header")
+        .header(Header::new("X-Csrf-Token", csrf_token.to_owned()))
+        .dispatch();
+    response.status() == Status::Ok
+}
+
+#[test]
+fn test_rotated_token_works_during_grace_window() {
+    let client = logged_in_client!();
+    let (old_token, new_token) = elevate_session(&client);
+
+    // Both tokens work for now, so requests racing the rotation do not fail
+    assert!(passes_header_check(&client, &new_token));
+    assert!(passes_header_check(&client, &old_token));
+
+    // And pages now render the new token
+    let text = client.get("/").dispatch().into_string().unwrap();
+    let rendered = rendered_csrf_token(&text);
+    assert_ne!(rendered, new_token);
+    assert!(passes_header_check(&client, &rendered));
+}
+
+#[test]
+fn test_rotated_token_stops_working_after_grace_window() {
+    let rocket = build_rocket();
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.rotation_grace_seconds", 0));
+    let client = logged_in_client!(rocket.configure(figment));
+    let (old_token, new_token) = elevate_session(&client);
+
+    assert!(passes_header_check(&client, &new_token));
+    assert!(!passes_header_check(&client, &old_token));
+}
+
+#[test]
+fn test_rotating_token_uses_configured_length() {
+    let length = |token: &str| {
+        base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+            .unwrap()
+            .len()
+    };
+    let mut token = RotatingCsrfToken::new(32).unwrap();
+    assert_eq!(length(token.current()), 32);
+    assert_eq!(length(token.rotate(48, 0).unwrap()), 48);
+}
+
+#[test]
+fn test_minted_tokens_use_configured_length() {
+    let rocket = build_rocket();
+    let figment = rocket.figment().clone().merge(("csrf.token_length", 32));
+    let client = logged_in_client!(rocket.configure(figment));
+    let length = |nonce: &str| {
+        base64::decode_config(nonce, base64::URL_SAFE_NO_PAD)
+            .unwrap()
+            .len()
+    };
+
+    let signed_token = client.get("/signed").dispatch().into_string().unwrap();
+    let [_, _, nonce, _] = token_parts(&signed_token);
+    assert_eq!(length(nonce), 32);
+
+    let one_time_token = client.get("/one-time").dispatch().into_string().unwrap();
+    let (_, nonce) = one_time_token.split_once('.').unwrap();
+    assert_eq!(length(nonce), 32);
+}
+
+/// Splits a signed token into its key ID, issue time, nonce and MAC.
+fn token_parts(token: &str) -> [&str; 4] {
+    token.split('.').collect::<Vec<_>>().try_into().unwrap()
+}
+
+#[test]
+fn test_signed_tokens_work() {
+    let client = logged_in_client!();
+
+    let response = client.get("/signed").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let signed_token = response.into_string().unwrap();
+
+    // A freshly minted token works
+    let response = client
+        .post("/signed")
+        .header(Header::new("X-Csrf-Token", signed_token.clone()))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // Tampering with any part of it does not
+    let [key_id, issued_at, nonce, mac] = token_parts(&signed_token);
+    let later = issued_at.parse::<i64>().unwrap() + 1;
+    for tampered in [
+        format!("{key_id}.{later}.{nonce}.{mac}"),
+        format!("{key_id}.{issued_at}.{nonce}A.{mac}"),
+        format!("{key_id}.{issued_at}.{nonce}.{}", &mac[1..]),
+        format!("other.{issued_at}.{nonce}.{mac}"),
+        "wrong_token".to_string(),
+    ] {
+        let response = client
+            .post("/signed")
+            .header(Header::new("X-Csrf-Token", tampered))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
+
+#[test]
+fn test_signed_tokens_are_bound_to_the_session() {
+    let client = logged_in_client!();
+    let other_client = logged_in_client!();
+
+    let other_token = other_client
+        .get("/signed")
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let response = client
+        .post("/signed")
+        .header(Header::new("X-Csrf-Token", other_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn test_signing_key_is_derived_from_decoded_secret_key() {
+    let key = |secret_key: Value| {
+        let rocket = rocket::build();
+        let figment = rocket.figment().clone().merge(("secret_key", secret_key));
+        CsrfSigningKey::from_rocket(&rocket.configure(figment))
+    };
+    let material: Vec<u8> = (1..=32).collect();
+    let sign = |key: CsrfSigningKey| key.sign("session", 0, "nonce");
+
+    let from_base64 = key(base64::encode(&material).into()).unwrap();
+    let from_hex = key(hex::encode(&material).into()).unwrap();
+    let from_bytes = key(material.clone().into()).unwrap();
+    assert_eq!(sign(from_base64), sign(from_hex.clone()));
+    assert_eq!(sign(from_hex), sign(from_bytes));
+
+    // Too short, or not encoded the way Rocket expects.
+    assert!(key(material[..16].to_vec().into()).is_err());
+    assert!(key("not-a-secret-key".into()).is_err());
+    assert!(key(String::from_utf8(vec![b'z'; 64]).unwrap().into()).is_err());
+}
+
+#[test]
+fn test_xsrf_cookie_can_be_echoed_in_header() {
+    let client = Client::tracked(build_rocket()).unwrap();
+
+    let response = client.get("/xsrf").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    // The cookie is neither encrypted nor HttpOnly, so scripts can read it
+    let cookie = response.cookies().get("XSRF-TOKEN").unwrap().clone();
+    assert!(!cookie.http_only().unwrap_or(false));
+    let csrf_token = cookie.value().to_owned();
+
+    // Echoing it back works, as often as needed
+    for _ in 0..2 {
+        let response = client
+            .post("/xsrf")
+            .header(Header::new("X-Csrf-Token", csrf_token.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    // But not without the header, or with another value in it
+    let response = client.post("/xsrf").dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = client
+        .post("/xsrf")
+        .header(Header::new("X-Csrf-Token", "wrong_token"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn test_xsrf_cookie_must_be_signed() {
+    let client = Client::tracked(build_rocket()).unwrap();
+    let cookie = client
+        .get("/xsrf")
+        .dispatch()
+        .cookies()
+        .get("XSRF-TOKEN")
+        .unwrap()
+        .clone();
+    let csrf_token = cookie.value().to_owned();
+
+    // Cookies which the server did not mint are rejected, even if the header matches them
+    let [key_id, issued_at, nonce, mac] = token_parts(&csrf_token);
+    let later = issued_at.parse::<i64>().unwrap() + 1;
+    for forged in [
+        format!("{key_id}.{later}.{nonce}.{mac}"),
+        format!("{key_id}.{issued_at}.{nonce}A.{mac}"),
+        "attacker_chosen_token".to_string(),
+    ] {
+        let response = client
+            .post("/xsrf")
+            .cookie(Cookie::new("XSRF-TOKEN", forged.clone()))
+            .header(Header::new("X-Csrf-Token", forged))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    // Including ones signed by another instance with a different key
+    let other_client = Client::tracked(build_rocket()).unwrap();
+    let response = other_client
+        .post("/xsrf")
+        .cookie(cookie)
+        .header(Header::new("X-Csrf-Token", csrf_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[get("/xsrf")]
+fn global_xsrf_token(csrf_token: SetXsrfCookieCsrfToken) -> String {
+    csrf_token.set().to_owned()
+}
+
+#[test]
+fn test_xsrf_cookie_is_only_accepted_from_header() {
+    let client = Client::tracked(
+        rocket::build()
+            .mount("/", routes![global_xsrf_token, global_post])
+            .attach(CsrfConfig::fairing())
+            .attach(CsrfSigningKey::fairing())
+            .attach(CsrfFairing::<XsrfCookieCsrfToken>::new()),
+    )
+    .unwrap();
+    let csrf_token = client.get("/xsrf").dispatch().into_string().unwrap();
+
+    // A cross site form can carry a token planted in the cookie, but not set a header.
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(format!("csrf_token={csrf_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = client
+        .post("/")
+        .header(ContentType::JSON)
+        .body(format!(r#"{{"csrf_token": "{csrf_token}"}}"#))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let response = client
+        .post("/")
+        .header(Header::new("X-CSRF-Token", csrf_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+const SECRET_1: &str = "1111111111111111111111111111111111111111111111111111111111111111";
+const SECRET_2: &str = "MjIyMjIyMjIyMjIyMjIyMjIyMjIyMjIyMjIyMjIyMjI=";
+const OTHER_SECRET: &str = "3333333333333333333333333333333333333333333333333333333333333333";
+
+/// Builds the example app with the given signing keys, as `(id, secret, status)`.
+fn build_rocket_with_keys(keys: &[(&str, &str, &str)]) -> Rocket<Build> {
+    let keys: Vec<HashMap<&str, &str>> = keys
+        .iter()
+        .map(|(id, secret, status)| {
+            HashMap::from([("id", *id), ("secret", *secret), ("status", *status)])
+        })
+        .collect();
+    let rocket = build_rocket();
+    let figment = rocket.figment().clone().merge(("csrf.signing_keys", keys));
+    rocket.configure(figment)
+}
+
+/// Checks an XSRF cookie with the client's keyring, returning the ID of the key which signed it.
+fn xsrf_signing_key_id(
+    client: &Client,
+    cookie: &Cookie<'static>,
+) -> Result<String, CsrfTokenVerificationError> {
+    let keyring = client.rocket().state::<CsrfKeyring>().unwrap();
+    keyring
+        .verify(cookie.value(), 600, |key, issued_at, nonce| {
+            key.sign_unbound(issued_at, nonce)
+        })
+        .map(str::to_owned)
+}
+
+fn passes_xsrf_check(client: &Client, cookie: &Cookie<'static>) -> bool {
+    let response = client
+        .post("/xsrf")
+        .cookie(cookie.clone())
+        .header(Header::new("X-Csrf-Token", cookie.value().to_owned()))
+        .dispatch();
+    response.status() == Status::Ok
+}
+
+#[get("/xsrf/debug")]
+fn debug_xsrf_cookie(csrf_token: XsrfCookieCsrfToken) -> String {
+    format!("{csrf_token:?}")
+}
+
+#[test]
+fn test_xsrf_cookie_is_not_logged() {
+    let client = Client::tracked(build_rocket().mount("/", routes![debug_xsrf_cookie])).unwrap();
+    let response = client.get("/xsrf").dispatch();
+    let cookie = response.cookies().get("XSRF-TOKEN").unwrap().clone();
+    let debug = client
+        .get("/xsrf/debug")
+        .cookie(cookie.clone())
+        .dispatch()
+        .into_string()
+        .unwrap();
+    assert!(debug.starts_with("XsrfCookieCsrfToken"), "{debug}");
+    assert!(!debug.contains(cookie.value()), "{debug}");
+}
+
+#[test]
+fn test_keyring_verifies_tokens_from_older_keys() {
+    let client = Client::tracked(build_rocket_with_keys(&[("k1", SECRET_1, "primary")])).unwrap();
+    let cookie = client
+        .get("/xsrf")
+        .dispatch()
+        .cookies()
+        .get("XSRF-TOKEN")
+        .unwrap()
+        .clone();
+    assert!(cookie.value().starts_with("k1."));
+
+    // After rotating, new tokens are signed with the new key, while old ones keep working
+    let client = Client::tracked(build_rocket_with_keys(&[
+        ("k2", SECRET_2, "primary"),
+        ("k1", SECRET_1, "verify"),
+    ]))
+    .unwrap();
+    let keyring = client.rocket().state::<CsrfKeyring>().unwrap();
+    assert_eq!(keyring.primary_key_id(), "k2");
+    assert!(passes_xsrf_check(&client, &cookie));
+    assert_eq!(xsrf_signing_key_id(&client, &cookie).unwrap(), "k1");
+    let new_cookie = client
+        .get("/xsrf")
+        .dispatch()
+        .cookies()
+        .get("XSRF-TOKEN")
+        .unwrap()
+        .clone();
+    assert!(new_cookie.value().starts_with("k2."));
+    assert!(passes_xsrf_check(&client, &new_cookie));
+    assert_eq!(xsrf_signing_key_id(&client, &new_cookie).unwrap(), "k2");
+
+    // Until the old key is retired, which is reported as long as its secret is kept
+    let client = Client::tracked(build_rocket_with_keys(&[
+        ("k2", SECRET_2, "primary"),
+        ("k1", SECRET_1, "retired"),
+    ]))
+    .unwrap();
+    assert!(!passes_xsrf_check(&client, &cookie));
+    assert!(matches!(
+        xsrf_signing_key_id(&client, &cookie),
+        Err(CsrfTokenVerificationError::RetiredKey)
+    ));
+    assert_eq!(xsrf_signing_key_id(&client, &new_cookie).unwrap(), "k2");
+    // Forged tokens claiming to be from the retired key are just invalid
+    let [_, issued_at, nonce, _] = token_parts(cookie.value());
+    let forged = Cookie::new("XSRF-TOKEN", format!("k1.{issued_at}.{nonce}.Zm9yZ2Vk"));
+    assert!(matches!(
+        xsrf_signing_key_id(&client, &forged),
+        Err(CsrfTokenVerificationError::CsrfTokenMismatch)
+    ));
+
+    // Once the secret is removed, or the key is dropped entirely, tokens it signed cannot be
+    // told apart from forged ones
+    for keys in [
+        &[("k2", SECRET_2, "primary"), ("k1", "", "retired")][..],
+        &[("k2", SECRET_2, "primary")][..],
+    ] {
+        let client = Client::tracked(build_rocket_with_keys(keys)).unwrap();
+        assert!(!passes_xsrf_check(&client, &cookie));
+        assert!(matches!(
+            xsrf_signing_key_id(&client, &cookie),
+            Err(CsrfTokenVerificationError::CsrfTokenMismatch)
+        ));
+        assert!(passes_xsrf_check(&client, &new_cookie));
+    }
+
+    // Tokens must name the key which signed them, even the default one
+    let client = Client::tracked(build_rocket()).unwrap();
+    let default_cookie = client
+        .get("/xsrf")
+        .dispatch()
+        .cookies()
+        .get("XSRF-TOKEN")
+        .unwrap()
+        .clone();
+    assert!(passes_xsrf_check(&client, &default_cookie));
+    let unnamed = default_cookie
+        .value()
+        .strip_prefix(&format!("{DEFAULT_SIGNING_KEY_ID}."))
+        .unwrap();
+    let unnamed = Cookie::new("XSRF-TOKEN", unnamed.to_owned());
+    assert!(!passes_xsrf_check(&client, &unnamed));
+
+    // A key with the same ID but another secret does not verify the old tokens either
+    let client =
+        Client::tracked(build_rocket_with_keys(&[("k1", OTHER_SECRET, "primary")])).unwrap();
+    assert!(!passes_xsrf_check(&client, &cookie));
+}
+
+#[test]
+fn test_invalid_keyring_fails_launch() {
+    for keys in [
+        &[("k1", SECRET_1, "primary"), ("k2", SECRET_2, "primary")][..],
+        &[("k1", SECRET_1, "verify")][..],
+        &[("k1", "", "primary")][..],
+        &[("k.1", SECRET_1, "primary")][..],
+        &[("k1", SECRET_1, "primary"), ("k1", SECRET_2, "verify")][..],
+        // Secrets are decoded like `secret_key`, and must be long enough.
+        &[("k1", "a", "primary")][..],
+        &[("k1", "secret 1", "primary")][..],
+        &[("k1", "11111111111111111111111111111111", "primary")][..],
+        &[(
+            "k1",
+            "zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz",
+            "primary",
+        )][..],
+    ] {
+        let error = Client::tracked(build_rocket_with_keys(keys)).err().unwrap();
+        assert!(matches!(
+            error.kind(),
+            rocket::error::ErrorKind::FailedFairings(_)
+        ));
+    }
+}
+
+fn signup<'c>(client: &'c Client, csrf_token: &str) -> LocalResponse<'c> {
+    client
+        .post("/signup")
+        .header(ContentType::Form)
+        .body(format!(
+            "csrf_token={csrf_token}&name=Hasnain&password=hunter2"
+        ))
+        .dispatch()
+}
+
+#[test]
+fn test_recoverable_form_can_be_resubmitted() {
+    let client = Client::tracked(build_rocket()).unwrap();
+    client.get("/signup").dispatch();
+    let response = signup(&client, "wrong_token");
+    assert_eq!(response.status(), Status::Forbidden);
+    let text = response.into_string().unwrap();
+    assert!(text.contains("This form could not be verified."));
+    assert!(text.contains("value=\"Hasnain\""));
+    assert!(!text.contains("hunter2"));
+
+    let response = signup(&client, &rendered_csrf_token(&text));
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "Welcome, Hasnain!");
+}
+
+#[test]
+fn test_recoverable_form_explains_expired_tokens_and_missing_cookies() {
+    let client = Client::tracked(build_rocket()).unwrap();
+    let now = rocket::time::OffsetDateTime::now_utc().unix_timestamp();
+    let expired_token = format!("{}.600.0.expired", now - 601);
+    let text = client
+        .post("/signup")
+        .private_cookie(Cookie::new("__Host-csrf-token", expired_token.clone()))
+        .header(ContentType::Form)
+        .body(format!(
+            "csrf_token={expired_token}&name=Hasnain&password=hunter2"
+        ))
+        .dispatch()
+        .into_string()
+        .unwrap();
+    assert!(text.contains("This form expired before it was submitted."));
+
+    // The token is fresh, yet the browser did not send the cookie back.
+    let client = Client::untracked(build_rocket()).unwrap();
+    let text = client.get("/signup").dispatch().into_string().unwrap();
+    let text = signup(&client, &rendered_csrf_token(&text))
+        .into_string()
+        .unwrap();
+    assert!(text.contains("Enable cookies for this site"));
+}
+
+#[test]
+fn test_one_time_tokens_can_only_be_used_once() {
+    let client = logged_in_client!();
+    let other_client = logged_in_client!();
+    let issue = |client: &Client| client.get("/one-time").dispatch().into_string().unwrap();
+    let post = |token: &str| {
+        client
+            .post("/one-time")
+            .header(Header::new("X-Csrf-Token", token.to_owned()))
+            .dispatch()
+            .status()
+    };
+
+    let first = issue(&client);
+    let second = issue(&client);
+    assert_ne!(first, second);
+    assert_eq!(post(&first), Status::Ok);
+    assert_eq!(post(&first), Status::Forbidden);
+    // Other tokens for the session are unaffected.
+    assert_eq!(post(&second), Status::Ok);
+    // Tokens are bound to the session they were issued for.
+    assert_eq!(post(&issue(&other_client)), Status::Forbidden);
+    assert_eq!(post("1.made-up"), Status::Forbidden);
+}
+
+#[rocket::async_test]
+async fn test_in_memory_nonce_store() {
+    let now = rocket::time::OffsetDateTime::now_utc().unix_timestamp();
+    let store = InMemoryNonceStore::new();
+    store.insert("session", "token", now + 60).await.unwrap();
+    store.insert("session", "expired", now - 1).await.unwrap();
+    assert_eq!(store.len(), 2);
+
+    let consume = |session, token| store.consume(session, token);
+    assert_eq!(
+        consume("other", "token").await.unwrap(),
+        NonceStatus::Unknown
+    );
+    assert_eq!(
+        consume("session", "token").await.unwrap(),
+        NonceStatus::Fresh
+    );
+    assert_eq!(
+        consume("session", "token").await.unwrap(),
+        NonceStatus::AlreadyUsed
+    );
+    assert_eq!(
+        consume("session", "expired").await.unwrap(),
+        NonceStatus::Unknown
+    );
+    assert_eq!(
+        consume("session", "never").await.unwrap(),
+        NonceStatus::Unknown
+    );
+}
+
+#[test]
+fn test_action_scoped_tokens_only_work_for_their_action() {
+    let client = logged_in_client!();
+    let post = |token: String| {
+        client
+            .post("/scoped")
+            .header(Header::new("X-Csrf-Token", token))
+            .dispatch()
+            .status()
+    };
+
+    let scoped_token = client.get("/scoped").dispatch().into_string().unwrap();
+    assert_eq!(post(scoped_token), Status::Ok);
+
+    // The session's own token is not accepted, nor is one scoped to another action.
+    let session_token = rendered_csrf_token(&client.get("/").dispatch().into_string().unwrap());
+    assert_eq!(post(session_token.clone()), Status::Forbidden);
+    let response = client
+        .get("/header")
+        .header(Header::new("X-Csrf-Token", session_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let other_client = logged_in_client!();
+    let other_token = other_client
+        .get("/scoped")
+        .dispatch()
+        .into_string()
+        .unwrap();
+    assert_eq!(post(other_token), Status::Forbidden);
+}
+
+#[test]
+fn test_action_scope_includes_method_and_route() {
+    assert_eq!(
+        csrf_action_scope(Method::Post, "/users/<id>/delete"),
+        "POST /users/<id>/delete"
+    );
+    let token = |scope: &str| action_scoped_token("session token", scope);
+    assert_eq!(token("POST /logout"), token("POST /logout"));
+    assert_ne!(token("POST /logout"), token("DELETE /logout"));
+    assert_ne!(token("POST /logout"), token("POST /login"));
+}
+
+#[test]
+fn test_login_fails_with_expired_double_submit_cookie() {
+    let (client, mut cookie, _) = fetch_login_page!();
+    let now = rocket::time::OffsetDateTime::now_utc().unix_timestamp();
+
+    // A cookie that is still within its maximum age works
+    let csrf_token = format!("{now}.600.0.not_expired");
+    cookie.set_value(csrf_token.clone());
+    let response = client
+        .post("/")
+        .private_cookie(cookie.clone())
+        .header(ContentType::Form)
+        .body(format!("name=Hasnain&csrf_token={csrf_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::SeeOther);
+
+    // But an old one is rejected, even though the token matches
+    let csrf_token = format!("{}.600.0.expired", now - 601);
+    cookie.set_value(csrf_token.clone());
+    let response = client
+        .post("/")
+        .private_cookie(cookie)
+        .header(ContentType::Form)
+        .body(format!("name=Hasnain&csrf_token={csrf_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+struct FixedExpectedToken {
+    issued_at: i64,
+}
+
+impl VerifierWithKnownExpectedToken for FixedExpectedToken {
+    type Proof = CsrfCheckProof;
+
+    fn expected_token(&self) -> &str {
+        "expected_token"
+    }
+
+    fn issued_at(&self) -> Option<i64> {
+        Some(self.issued_at)
+    }
+
+    fn max_age_seconds(&self) -> Option<i64> {
+        Some(60)
+    }
+}
+
+#[rocket::async_test]
+async fn test_known_expected_token_expiry() {
+    let now = rocket::time::OffsetDateTime::now_utc().unix_timestamp();
+    let token =
+        ManuallySourcedCsrfToken_DO_NOT_USE_UNLESS_YOU_ARE_SURE::new("expected_token".to_string());
+
+    let fresh = FixedExpectedToken { issued_at: now };
+    let proof = fresh.verify(&token).await.unwrap();
+    assert_eq!(proof.mechanism(), CsrfMechanism::FormField);
+    assert!(proof.verifier().ends_with("::FixedExpectedToken"));
+    assert!(proof.verified_at() >= now);
+
+    let expired = FixedExpectedToken {
+        issued_at: now - 61,
+    };
+    assert!(matches!(
+        expired.verify(&token).await,
+        Err(CsrfTokenVerificationError::Expired)
+    ));
+
+    // Mismatches are still reported as such, even when expired
+    let wrong = ManuallySourcedCsrfToken_DO_NOT_USE_UNLESS_YOU_ARE_SURE::new("wrong".to_string());
+    assert!(matches!(
+        expired.verify(&wrong).await,
+        Err(CsrfTokenVerificationError::CsrfTokenMismatch)
+    ));
+}
+
+#[test]
+fn test_csrf_config_is_read_from_rocket_config() {
+    let rocket = build_rocket();
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.cookie_name", "__Host-custom-csrf"))
+        .merge(("csrf.header_name", "X-Custom-Csrf"))
+        .merge(("csrf.token_length", 32))
+        .merge(("csrf.expiry_seconds", 60));
+    let client = Client::tracked(rocket.configure(figment)).unwrap();
+
+    let response = client.get("/").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let cookies = response.cookies();
+    assert!(cookies.get_private("__Host-csrf-token").is_none());
+    let cookie = cookies.get_private("__Host-custom-csrf").unwrap();
+    assert_eq!(cookie.max_age(), Some(rocket::time::Duration::seconds(60)));
+    let csrf_token = cookie.value().to_owned();
+    let (_, random) = csrf_token.rsplit_once('.').unwrap();
+    assert_eq!(
+        base64::decode_config(random, base64::URL_SAFE_NO_PAD)
+            .unwrap()
+            .len(),
+        32
+    );
+    assert!(csrf_token.contains(".60."));
+
+    // The custom cookie is picked up when verifying
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(format!("name=Hasnain&csrf_token={csrf_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::SeeOther);
+
+    // And the custom header is used for header checks
+    let text = client.get("/").dispatch().into_string().unwrap();
+    let session_csrf_token = rendered_csrf_token(&text);
+    let response = client
+        .get("/header")
+        .header(Header::new("X-Csrf-Token", session_csrf_token.clone()))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = client
+        .get("/header")
+        .header(Header::new("X-Custom-Csrf", session_csrf_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn test_invalid_csrf_config_fails_launch() {
+    let invalid: [(&str, Value); 7] = [
+        ("csrf.token_length", "not a number".into()),
+        ("csrf.token_length", 0.into()),
+        ("csrf.token_length", 8.into()),
+        ("csrf.token_length", 127.into()),
+        ("csrf.expiry_seconds", 0.into()),
+        ("csrf.none_expiry_seconds", (-20).into()),
+        ("csrf.rotation_grace_seconds", (-1).into()),
+    ];
+    for (key, value) in invalid {
+        let rocket = build_rocket();
+        let figment = rocket.figment().clone().merge((key, value));
+        let error = Client::tracked(rocket.configure(figment)).err().unwrap();
+        assert!(
+            matches!(error.kind(), rocket::error::ErrorKind::FailedFairings(_)),
+            "{key}"
+        );
+    }
+
+    // No grace window is fine.
+    let rocket = build_rocket();
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.rotation_grace_seconds", 0));
+    assert!(Client::tracked(rocket.configure(figment)).is_ok());
+}
+
+#[test]
+fn test_longest_token_length_fits_in_peeked_body() {
+    let rocket = rocket::build()
+        .mount("/", routes![global_token, global_post])
+        .attach(CsrfConfig::fairing())
+        .attach(CsrfFairing::<DoubleSubmitCookieCsrfToken>::new());
+    let figment = rocket.figment().clone().merge(("csrf.token_length", 126));
+    let client = Client::tracked(rocket.configure(figment)).unwrap();
+
+    // Padding makes sure the token is found without reading the whole body.
+    let padding = "x".repeat(1024);
+    for content_type in [ContentType::Form, ContentType::JSON] {
+        let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+        let body = if content_type == ContentType::Form {
+            format!("csrf_token={csrf_token}&padding={padding}")
+        } else {
+            format!(r#"{{"csrf_token":"{csrf_token}","padding":"{padding}"}}"#)
+        };
+        let token_end = body.find(&csrf_token).unwrap() + csrf_token.len();
+        assert!(token_end <= crate::extract::PEEK_BYTES, "{content_type}");
+        let response = client.post("/").header(content_type).body(body).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+}
+
+#[get("/token")]
+fn global_token(csrf_token: SetDoubleSubmitCookieCsrfToken) -> String {
+    csrf_token.set_masked().unwrap()
+}
+
+#[get("/")]
+fn global_get() -> &'static str {
+    "no checks needed"
+}
+
+#[post("/", data = "<body>")]
+fn global_post(_proof: CsrfCheckProof, body: String) -> String {
+    body
+}
+
+#[put("/", data = "<body>")]
+fn global_put(_proof: CsrfCheckProof, body: String) -> String {
+    body
+}
+
+#[patch("/", data = "<body>")]
+fn global_patch(_proof: CsrfCheckProof, body: String) -> String {
+    body
+}
+
+#[delete("/")]
+fn global_delete(_proof: CsrfCheckProof) -> &'static str {
+    "deleted"
+}
+
+#[post("/webhook")]
+fn global_webhook() -> &'static str {
+    "webhook received"
+}
+
+#[post("/<_>/callback")]
+fn global_callback() -> &'static str {
+    "callback received"
+}
+
+fn build_globally_protected_rocket() -> Rocket<Build> {
+    let rocket = rocket::build();
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.exempt_routes", ["global_callback"]));
+    rocket
+        .configure(figment)
+        .mount(
+            "/",
+            routes![
+                global_token,
+                global_get,
+                global_post,
+                global_put,
+                global_patch,
+                global_delete,
+            ],
+        )
+        .mount("/exempt", routes![global_webhook, global_callback])
+        .attach(CsrfConfig::fairing())
+        .attach(CsrfFairing::<DoubleSubmitCookieCsrfToken>::new().exempt("global_webhook"))
+}
+
+#[test]
+fn test_fairing_rejects_unsafe_requests_without_token() {
+    let client = Client::tracked(build_globally_protected_rocket()).unwrap();
+    client.get("/token").dispatch();
+
+    for method in [Method::Post, Method::Put, Method::Patch, Method::Delete] {
+        let response = client.req(method, "/").dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    // Safe methods are left alone
+    let response = client.get("/").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn test_fairing_accepts_token_from_header() {
+    let client = Client::tracked(build_globally_protected_rocket()).unwrap();
+    for method in [Method::Post, Method::Put, Method::Patch, Method::Delete] {
+        let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+        let response = client
+            .req(method, "/")
+            .header(Header::new("X-CSRF-Token", csrf_token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    let client = Client::tracked(build_globally_protected_rocket()).unwrap();
+    client.get("/token").dispatch();
+    let response = client
+        .post("/")
+        .header(Header::new("X-CSRF-Token", "wrong_token"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn test_fairing_accepts_token_from_form() {
+    let client = Client::tracked(build_globally_protected_rocket()).unwrap();
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let body = format!("csrf_token={csrf_token}&name=Hasnain");
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    // The route still gets the whole body
+    assert_eq!(response.into_string().unwrap(), body);
+
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(format!("name=Hasnain&csrf_token={csrf_token}x"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+/// Only accepts transfers which passed their check with a token from a form field.
+#[post("/transfer")]
+fn transfer(proof: CsrfCheckProof) -> Result<&'static str, Status> {
+    if proof.report_only() || proof.mechanism() != CsrfMechanism::FormField {
+        return Err(Status::Forbidden);
+    }
+    Ok(proof.verifier())
+}
+
+#[test]
+fn test_proof_records_how_the_request_was_checked() {
+    let client = Client::tracked(
+        rocket::build()
+            .mount("/", routes![global_token, transfer])
+            .attach(CsrfFairing::<DoubleSubmitCookieCsrfToken>::new()),
+    )
+    .unwrap();
+
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let response = client
+        .post("/transfer")
+        .header(ContentType::Form)
+        .body(format!("csrf_token={csrf_token}&amount=100"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_string().unwrap(),
+        "rocket_csrf_guard::cookie::DoubleSubmitCookieCsrfToken"
+    );
+
+    // The token is just as valid in a header, but the route wants a form.
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let response = client
+        .post("/transfer")
+        .header(Header::new("X-CSRF-Token", csrf_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn test_forged_proofs_are_marked() {
+    let proof = CsrfCheckProof::forged_DO_NOT_USE_UNLESS_YOU_ARE_SURE(CsrfMechanism::FormField);
+    assert_eq!(proof.mechanism(), CsrfMechanism::FormField);
+    assert_eq!(proof.verifier(), "forged");
+    assert!(!proof.report_only());
+
+    // Routes only get proofs from the request local cache.
+    let client = Client::tracked(rocket::build().mount("/", routes![transfer])).unwrap();
+    let response = client
+        .post("/transfer")
+        .header(ContentType::Form)
+        .body("csrf_token=anything&amount=100")
+        .dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+}
+
+#[test]
+fn test_fairing_only_reads_the_start_of_the_body() {
+    let client = Client::tracked(build_globally_protected_rocket()).unwrap();
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let padding = "a".repeat(1024);
+    let response = client
+        .post("/")
+        .header(ContentType::Form)
+        .body(format!("padding={padding}&csrf_token={csrf_token}"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn test_fairing_accepts_token_from_json() {
+    let client = Client::tracked(build_globally_protected_rocket()).unwrap();
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let body = format!("{{\"csrf_token\": \"{csrf_token}\", \"name\": \"Hasnain\"}}");
+    let response = client
+        .put("/")
+        .header(ContentType::JSON)
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), body);
+
+    client.get("/token").dispatch();
+    let response = client
+        .put("/")
+        .header(ContentType::JSON)
+        .body("{\"csrf_token\": \"wrong_token\"}")
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[test]
+fn test_fairing_only_reads_top_level_json_token_key() {
+    let client = Client::tracked(build_globally_protected_rocket()).unwrap();
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let body = format!(
+        "{{\"not_csrf_token\": \"wrong_token\", \"nested\": {{\"csrf_token\": \"wrong_token\"}}, \
+         \"list\": [1, \"]\", true], \"csrf_token\": \"{csrf_token}\"}}"
+    );
+    let response = client
+        .put("/")
+        .header(ContentType::JSON)
+        .body(&body)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let bodies: [fn(&str) -> String; 4] = [
+        |token| format!("{{\"not_csrf_token\": \"{token}\"}}"),
+        |token| format!("{{\"nested\": {{\"csrf_token\": \"{token}\"}}}}"),
+        |token| format!("{{\"note\": \"\\\"csrf_token\\\": \\\"{token}\\\"\"}}"),
+        |token| format!("[{{\"csrf_token\": \"{token}\"}}]"),
+    ];
+    for body in bodies {
+        let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+        let response = client
+            .put("/")
+            .header(ContentType::JSON)
+            .body(body(&csrf_token))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
+
+#[test]
+fn test_fairing_skips_exempt_routes() {
+    let client = Client::tracked(build_globally_protected_rocket()).unwrap();
+
+    // Exempted through the fairing
+    let response = client.post("/exempt/webhook").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "webhook received");
+
+    // Exempted through the config
+    let response = client.post("/exempt/github/callback").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "callback received");
+
+    // Other routes, even under the same mount point, are still checked
+    for uri in ["/exempt/other", "/exempt/webhook/extra", "/exempt/callback"] {
+        let response = client.post(uri).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+    let response = client.post("/").dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[post("/<_id>")]
+fn overlapping_exempt_item(_id: u32) -> &'static str {
+    "item updated"
+}
+
+#[post("/transfer")]
+fn overlapping_transfer() -> &'static str {
+    "money transferred"
+}
+
+#[test]
+fn test_fairing_refuses_exemptions_overlapping_checked_routes() {
+    let rocket = rocket::build()
+        .mount("/", routes![overlapping_exempt_item, overlapping_transfer])
+        .attach(CsrfConfig::fairing())
+        .attach(
+            CsrfFairing::<DoubleSubmitCookieCsrfToken>::new().exempt("overlapping_exempt_item"),
+        );
+    let client = Client::tracked(rocket).unwrap();
+
+    // `POST /transfer` matches the exempt `POST /<_id>` as well, so nothing is exempt.
+    for uri in ["/transfer", "/42"] {
+        let response = client.post(uri).dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
+
+fn double_submit_policy_rocket(policy: &str) -> Rocket<Build> {
+    let rocket = build_globally_protected_rocket();
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.double_submit_policy", policy));
+    rocket
+        .configure(figment)
+        .attach(DoubleSubmitCookieCsrfToken::reissue_fairing())
+}
+
+fn double_submit_policy_client(policy: &str) -> Client {
+    Client::tracked(double_submit_policy_rocket(policy)).unwrap()
+}
+
+/// Sends several requests with the same double submit cookie, like requests which are all in
+/// flight before the browser sees the response to the first one. Returns the response status
+/// and reissued token of each.
+fn submit_concurrently(policy: &str) -> Vec<(Status, Option<String>)> {
+    let client = Client::untracked(double_submit_policy_rocket(policy)).unwrap();
+    let response = client.get("/token").dispatch();
+    let cookie = response
+        .cookies()
+        .get_private("__Host-csrf-token")
+        .unwrap()
+        .clone();
+    let csrf_token = response.into_string().unwrap();
+    (0..3)
+        .map(|_| {
+            let response = client
+                .post("/")
+                .private_cookie(cookie.clone())
+                .header(Header::new("X-CSRF-Token", csrf_token.clone()))
+                .dispatch();
+            let reissued = response
+                .headers()
+                .get_one("X-CSRF-Token")
+                .map(str::to_owned);
+            (response.status(), reissued)
+        })
+        .collect()
+}
+
+/// Opens the form in several tabs, returning the token each of them rendered.
+fn open_tabs(client: &Client) -> Vec<String> {
+    (0..3)
+        .map(|_| client.get("/token").dispatch().into_string().unwrap())
+        .collect()
+}
+
+fn submit(client: &Client, csrf_token: &str) -> Status {
+    client
+        .post("/")
+        .header(Header::new("X-CSRF-Token", csrf_token.to_owned()))
+        .dispatch()
+        .status()
+}
+
+#[test]
+fn test_consumed_double_submit_cookie_only_works_once() {
+    let client = double_submit_policy_client("consume");
+    let tabs = open_tabs(&client);
+
+    // Every tab replaced the cookie, so only the last one can submit, and only once
+    assert_eq!(submit(&client, &tabs[2]), Status::Ok);
+    for csrf_token in &tabs {
+        assert_eq!(submit(&client, csrf_token), Status::Forbidden);
+    }
+}
+
+#[test]
+fn test_consumed_double_submit_cookie_is_only_removed_by_the_browser() {
+    // The cookie is removed by the response, so requests sent before the browser sees it
+    // still carry the cookie, and all pass.
+    for (status, reissued) in submit_concurrently("consume") {
+        assert_eq!(status, Status::Ok);
+        assert_eq!(reissued, None);
+    }
+}
+
+#[test]
+fn test_kept_double_submit_cookie_works_for_all_tabs() {
+    let client = double_submit_policy_client("keep");
+    let tabs = open_tabs(&client);
+
+    // All tabs share the cookie, which stays around after being used
+    for csrf_token in tabs.iter().chain(&tabs) {
+        assert_eq!(submit(&client, csrf_token), Status::Ok);
+    }
+    let response = client.get("/").dispatch();
+    assert!(response.headers().get_one("X-CSRF-Token").is_none());
+
+    for (status, reissued) in submit_concurrently("keep") {
+        assert_eq!(status, Status::Ok);
+        assert_eq!(reissued, None);
+    }
+}
+
+#[test]
+fn test_rotated_double_submit_cookie_is_reissued() {
+    let client = double_submit_policy_client("rotate");
+    let tabs = open_tabs(&client);
+
+    // The first submission replaces the cookie, and the new token comes back in the response
+    let response = client
+        .post("/")
+        .header(Header::new("X-CSRF-Token", tabs[0].clone()))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let mut csrf_token = response
+        .headers()
+        .get_one("X-CSRF-Token")
+        .unwrap()
+        .to_owned();
+
+    // So the other tabs' tokens no longer work
+    for stale in &tabs {
+        assert_eq!(submit(&client, stale), Status::Forbidden);
+    }
+
+    // But a script following along with the reissued tokens can keep submitting
+    for _ in 0..3 {
+        let response = client
+            .post("/")
+            .header(Header::new("X-CSRF-Token", csrf_token.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        csrf_token = response
+            .headers()
+            .get_one("X-CSRF-Token")
+            .unwrap()
+            .to_owned();
+    }
+
+    // Requests in flight with the same cookie all pass, and each is sent its own new token
+    let submissions = submit_concurrently("rotate");
+    let mut reissued: Vec<_> = submissions
+        .into_iter()
+        .map(|(status, reissued)| {
+            assert_eq!(status, Status::Ok);
+            reissued.unwrap()
+        })
+        .collect();
+    reissued.sort();
+    reissued.dedup();
+    assert_eq!(reissued.len(), 3);
+}
+
+#[post("/checked-twice")]
+fn checked_twice(
+    _csrf_check: CheckCsrfProtectionHeader<DoubleSubmitCookieCsrfToken>,
+) -> &'static str {
+    "checked by the fairing and the route"
+}
+
+#[test]
+fn test_rotated_double_submit_cookie_is_only_rotated_once_per_request() {
+    let rocket = rocket::build()
+        .mount("/", routes![global_token, checked_twice])
+        .attach(CsrfFairing::<DoubleSubmitCookieCsrfToken>::new())
+        .attach(DoubleSubmitCookieCsrfToken::reissue_fairing());
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.double_submit_policy", "rotate"));
+    let client = Client::tracked(rocket.configure(figment).attach(CsrfConfig::fairing())).unwrap();
+
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let response = client
+        .post("/checked-twice")
+        .header(Header::new("X-CSRF-Token", csrf_token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let reissued = response.headers().get_one("X-CSRF-Token").unwrap();
+    let reissued = String::from_utf8(unmask_token(reissued).unwrap()).unwrap();
+    let cookie = response.cookies().get_private("__Host-csrf-token").unwrap();
+    assert_eq!(cookie.value(), reissued);
+    let metrics = client.rocket().state::<CsrfMetrics>().unwrap();
+    assert_eq!(metrics.tokens_issued(), 2);
+}
+
+fn audited_protection(client: &Client) -> Vec<(String, RouteCsrfProtection)> {
+    client
+        .rocket()
+        .state::<CsrfAuditReport>()
+        .unwrap()
+        .routes
+        .iter()
+        .map(|route| (route.name.clone().unwrap(), route.protection))
+        .collect()
+}
+
+#[test]
+fn test_audit_warns_about_undeclared_routes() {
+    let client =
+        Client::tracked(build_rocket().attach(CsrfAudit::warn().protected("do_login"))).unwrap();
+    let mut routes = audited_protection(&client);
+    routes.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        routes,
+        vec![
+            (
+                "check_one_time_csrf_header".to_owned(),
+                RouteCsrfProtection::Missing
+            ),
+            ("check_same_origin".to_owned(), RouteCsrfProtection::Missing),
+            (
+                "check_scoped_csrf_header".to_owned(),
+                RouteCsrfProtection::Missing
+            ),
+            (
+                "check_signed_csrf_header".to_owned(),
+                RouteCsrfProtection::Missing
+            ),
+            ("check_xsrf_header".to_owned(), RouteCsrfProtection::Missing),
+            ("do_login".to_owned(), RouteCsrfProtection::Declared),
+            ("do_logout".to_owned(), RouteCsrfProtection::Missing),
+            ("do_rename".to_owned(), RouteCsrfProtection::Missing),
+            ("do_signup".to_owned(), RouteCsrfProtection::Missing),
+            (
+                "elevate_privileges".to_owned(),
+                RouteCsrfProtection::Missing
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_audit_fails_launch_for_undeclared_routes() {
+    let error =
+        Client::tracked(build_rocket().attach(CsrfAudit::fail_launch().protected("do_login")))
+            .err()
+            .unwrap();
+    assert!(matches!(
+        error.kind(),
+        rocket::error::ErrorKind::FailedFairings(_)
+    ));
+
+    let rocket = build_rocket();
+    let figment = rocket.figment().clone().merge((
+        "csrf.protected_routes",
+        [
+            "do_logout",
+            "do_rename",
+            "check_signed_csrf_header",
+            "check_one_time_csrf_header",
+            "check_scoped_csrf_header",
+            "check_same_origin",
+            "check_xsrf_header",
+            "elevate_privileges",
+            "do_signup",
+        ],
+    ));
+    let audit = CsrfAudit::fail_launch().protected("do_login");
+    let client = Client::tracked(rocket.configure(figment).attach(audit)).unwrap();
+    let report = client.rocket().state::<CsrfAuditReport>().unwrap();
+    assert_eq!(report.unprotected().count(), 0);
+}
+
+#[test]
+fn test_audit_counts_routes_covered_by_the_fairing() {
+    let client =
+        Client::tracked(build_globally_protected_rocket().attach(CsrfAudit::fail_launch()))
+            .unwrap();
+    let routes = audited_protection(&client);
+    assert_eq!(routes.len(), 6);
+    for (name, protection) in routes {
+        let expected = match name.as_str() {
+            "global_webhook" | "global_callback" => RouteCsrfProtection::Exempt,
+            _ => RouteCsrfProtection::Fairing,
+        };
+        assert_eq!(protection, expected, "{name}");
+    }
+}
+
+#[test]
+fn test_audit_resolves_exemptions_like_the_fairing() {
+    let rocket = || {
+        rocket::build()
+            .mount("/", routes![overlapping_exempt_item, overlapping_transfer])
+            .attach(CsrfConfig::fairing())
+            .attach(
+                CsrfFairing::<DoubleSubmitCookieCsrfToken>::new().exempt("overlapping_exempt_item"),
+            )
+    };
+    let client = Client::tracked(rocket().attach(CsrfAudit::warn())).unwrap();
+    let mut routes = audited_protection(&client);
+    routes.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(
+        routes,
+        vec![
+            (
+                "overlapping_exempt_item".to_owned(),
+                RouteCsrfProtection::AmbiguousExemption
+            ),
+            (
+                "overlapping_transfer".to_owned(),
+                RouteCsrfProtection::Fairing
+            ),
+        ]
+    );
+    let error = Client::tracked(rocket().attach(CsrfAudit::fail_launch()))
+        .err()
+        .unwrap();
+    assert!(matches!(
+        error.kind(),
+        rocket::error::ErrorKind::FailedFairings(_)
+    ));
+}
+
+#[test]
+fn test_audit_reports_unknown_declared_routes() {
+    let audit = CsrfAudit::warn().protected("do_login").protected("do_logn");
+    let client = Client::tracked(build_rocket().attach(audit)).unwrap();
+    let report = client.rocket().state::<CsrfAuditReport>().unwrap();
+    assert_eq!(report.unknown_protected, vec!["do_logn".to_owned()]);
+
+    let audit = CsrfAudit::fail_launch()
+        .protected("do_logn")
+        .protected("global_webhook");
+    let error = Client::tracked(build_globally_protected_rocket().attach(audit))
+        .err()
+        .unwrap();
+    assert!(matches!(
+        error.kind(),
+        rocket::error::ErrorKind::FailedFairings(_)
+    ));
+}
+
+fn same_origin_client(config: &[(&str, &[&str])], missing_origin: &str) -> Client {
+    let rocket = build_rocket();
+    let mut figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.missing_origin", missing_origin));
+    for (key, value) in config {
+        figment = figment.merge((*key, *value));
+    }
+    Client::tracked(rocket.configure(figment)).unwrap()
+}
+
+#[test]
+fn test_same_origin_matches_host_without_configured_origins() {
+    let client = same_origin_client(&[], "check_referer");
+    let status = |headers: &[(&'static str, &'static str)]| {
+        let mut request = client.post("/origin");
+        request.set_host(Host::parse("localhost:8000").unwrap());
+        for (name, value) in headers {
+            request = request.header(Header::new(*name, *value));
+        }
+        request.dispatch().status()
+    };
+    assert_eq!(status(&[("Origin", "http://localhost:8000")]), Status::Ok);
+    assert_eq!(status(&[("Origin", "https://LOCALHOST:8000")]), Status::Ok);
+    assert_eq!(
+        status(&[("Referer", "http://localhost:8000/some/page?q=1")]),
+        Status::Ok
+    );
+    for origin in [
+        "http://localhost",
+        "http://localhost:8001",
+        "http://evil.com:8000",
+        "null",
+    ] {
+        let mut request = client.post("/origin").header(Header::new("Origin", origin));
+        request.set_host(Host::parse("localhost:8000").unwrap());
+        assert_eq!(request.dispatch().status(), Status::Forbidden, "{origin}");
+    }
+    assert_eq!(status(&[]), Status::Forbidden);
+    // The Origin header wins over the Referer.
+    assert_eq!(
+        status(&[
+            ("Origin", "http://evil.com"),
+            ("Referer", "http://localhost:8000/")
+        ]),
+        Status::Forbidden
+    );
+}
+
+#[test]
+fn test_same_origin_checks_configured_origins() {
+    let allowed: &[&str] = &["https://example.com", "https://*.example.com"];
+    let client = same_origin_client(&[("csrf.allowed_origins", allowed)], "reject");
+    let status = |name: &'static str, value: &'static str| {
+        client
+            .post("/origin")
+            .header(Header::new(name, value))
+            .dispatch()
+            .status()
+    };
+    for origin in [
+        "https://example.com",
+        "https://example.com:443",
+        "https://app.example.com",
+        "https://a.b.example.com",
+    ] {
+        assert_eq!(status("Origin", origin), Status::Ok, "{origin}");
+    }
+    for origin in [
+        "http://example.com",
+        "https://example.com:8443",
+        "https://evilexample.com",
+        "https://example.com.evil.com",
+    ] {
+        assert_eq!(status("Origin", origin), Status::Forbidden, "{origin}");
+    }
+    // Referers are not consulted when configured to reject a missing Origin.
+    assert_eq!(
+        status("Referer", "https://example.com/form"),
+        Status::Forbidden
+    );
+
+    let client = same_origin_client(&[("csrf.allowed_origins", allowed)], "allow");
+    assert_eq!(client.post("/origin").dispatch().status(), Status::Ok);
+    let response = client
+        .post("/origin")
+        .header(Header::new("Referer", "https://evil.com/form"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[post("/origin-proof")]
+fn same_origin_proof(_origin: CheckSameOrigin, proof: CsrfCheckProof) -> &'static str {
+    proof.verifier()
+}
+
+#[test]
+fn test_allowed_missing_origin_hands_out_no_proof() {
+    let rocket = rocket::build()
+        .mount("/", routes![same_origin_proof])
+        .attach(CsrfConfig::fairing());
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.allowed_origins", ["https://example.com"]))
+        .merge(("csrf.missing_origin", "allow"));
+    let client = Client::tracked(rocket.configure(figment)).unwrap();
+
+    let response = client
+        .post("/origin-proof")
+        .header(Header::new("Origin", "https://example.com"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_string().unwrap(),
+        "rocket_csrf_guard::origin::SameOriginVerifier"
+    );
+
+    // The origin check lets the request through, but nothing was checked, so the proof
+    // guard still forwards.
+    let response = client.post("/origin-proof").dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+}
+
+#[test]
+fn test_invalid_allowed_origin_fails_launch() {
+    let rocket = build_rocket();
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.allowed_origins", ["example.com"]));
+    let error = Client::tracked(rocket.configure(figment)).err().unwrap();
+    assert!(matches!(
+        error.kind(),
+        rocket::error::ErrorKind::FailedFairings(_)
+    ));
+}
+
+#[post("/api")]
+fn fetch_metadata_api(
+    _policy: FetchMetadataPolicy<DoubleSubmitCookieCsrfToken>,
+    _proof: CsrfCheckProof,
+) -> &'static str {
+    "api"
+}
+
+#[get("/page")]
+fn fetch_metadata_page(
+    _policy: NavigationalFetchMetadataPolicy<DoubleSubmitCookieCsrfToken>,
+) -> &'static str {
+    "page"
+}
+
+#[get("/api")]
+fn fetch_metadata_api_get(
+    _policy: FetchMetadataPolicy<DoubleSubmitCookieCsrfToken>,
+) -> &'static str {
+    "api"
+}
+
+fn fetch_metadata_client() -> Client {
+    let rocket = rocket::build().mount(
+        "/",
+        routes![
+            global_token,
+            fetch_metadata_api,
+            fetch_metadata_page,
+            fetch_metadata_api_get
+        ],
+    );
+    Client::tracked(rocket).unwrap()
+}
+
+#[test]
+fn test_fetch_metadata_rejects_cross_site_requests() {
+    let client = fetch_metadata_client();
+    let post = |site: &'static str| {
+        client
+            .post("/api")
+            .header(Header::new("Sec-Fetch-Site", site))
+            .header(Header::new("Sec-Fetch-Mode", "navigate"))
+            .dispatch()
+            .status()
+    };
+    assert_eq!(post("same-origin"), Status::Ok);
+    assert_eq!(post("same-site"), Status::Ok);
+    assert_eq!(post("none"), Status::Ok);
+    assert_eq!(post("cross-site"), Status::Forbidden);
+
+    let get = |uri: &'static str, mode: &'static str, dest: &'static str| {
+        client
+            .get(uri)
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .header(Header::new("Sec-Fetch-Mode", mode))
+            .header(Header::new("Sec-Fetch-Dest", dest))
+            .dispatch()
+            .status()
+    };
+    assert_eq!(get("/page", "navigate", "document"), Status::Ok);
+    assert_eq!(get("/page", "no-cors", "image"), Status::Forbidden);
+    assert_eq!(get("/page", "navigate", "embed"), Status::Forbidden);
+    assert_eq!(get("/api", "navigate", "document"), Status::Forbidden);
+}
+
+#[test]
+fn test_fetch_metadata_falls_back_to_tokens_for_older_browsers() {
+    let client = fetch_metadata_client();
+    assert_eq!(client.get("/api").dispatch().status(), Status::Ok);
+    // Without a double submit cookie there is no verifier to fall back to.
+    assert_eq!(client.post("/api").dispatch().status(), Status::BadRequest);
+
+    client.get("/token").dispatch();
+    let response = client
+        .post("/api")
+        .header(Header::new("X-CSRF-Token", "wrong"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let token = client.get("/token").dispatch().into_string().unwrap();
+    let response = client
+        .post("/api")
+        .header(Header::new("X-CSRF-Token", token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+fn report_only_client(key: &str, value: impl rocket::serde::Serialize) -> Client {
+    let rocket = build_globally_protected_rocket();
+    let figment = rocket.figment().clone().merge((key, value));
+    Client::tracked(rocket.configure(figment)).unwrap()
+}
+
+fn violation_counts(client: &Client) -> (u64, u64) {
+    let counts = client.rocket().state::<CsrfMetrics>().unwrap();
+    (counts.reported(), counts.enforced())
+}
+
+#[test]
+fn test_fairing_lets_violations_through_in_report_only_mode() {
+    let client = report_only_client("csrf.report_only", true);
+    client.get("/token").dispatch();
+    for method in [Method::Post, Method::Put, Method::Patch, Method::Delete] {
+        let response = client.req(method, "/").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+    assert_eq!(violation_counts(&client), (4, 0));
+
+    let client = report_only_client("csrf.report_only_routes", ["global_put"]);
+    client.get("/token").dispatch();
+    assert_eq!(client.put("/").dispatch().status(), Status::Ok);
+    assert_eq!(client.post("/").dispatch().status(), Status::Forbidden);
+    assert_eq!(violation_counts(&client), (1, 1));
+}
+
+#[test]
+fn test_report_only_proofs_are_marked() {
+    let rocket = rocket::build()
+        .mount("/", routes![global_token, global_post, transfer])
+        .attach(CsrfConfig::fairing())
+        .attach(CsrfFairing::<DoubleSubmitCookieCsrfToken>::new());
+    let figment = rocket.figment().clone().merge(("csrf.report_only", true));
+    let client = Client::tracked(rocket.configure(figment)).unwrap();
+    client.get("/token").dispatch();
+
+    // Routes which only need a proof still let the request through...
+    let response = client.post("/").body("csrf_token=wrong").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    // ...but ones which check for excused failures do not.
+    let response = client
+        .post("/transfer")
+        .header(ContentType::Form)
+        .body("csrf_token=wrong&amount=100")
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let response = client
+        .post("/transfer")
+        .header(ContentType::Form)
+        .body(format!("csrf_token={csrf_token}&amount=100"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(violation_counts(&client), (2, 0));
+}
+
+/// Reads the violation reported for the request, if any.
+struct ReportedViolation(Option<CsrfViolation>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ReportedViolation {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(Self(request.local_cache(|| None::<CsrfViolation>).clone()))
+    }
+}
+
+#[post("/report")]
+fn report_only_api(
+    _policy: FetchMetadataPolicy<DoubleSubmitCookieCsrfToken>,
+    violation: ReportedViolation,
+) -> String {
+    let violation = violation.0.unwrap();
+    format!(
+        "{} {} {} {} {}",
+        violation.method,
+        violation.route.unwrap(),
+        violation.origin.unwrap(),
+        violation.reason,
+        violation.report_only
+    )
+}
+
+#[test]
+fn test_guards_report_violations_on_report_only_routes() {
+    let rocket = rocket::build()
+        .mount("/", routes![fetch_metadata_api, report_only_api])
+        .attach(CsrfConfig::fairing());
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("csrf.report_only_routes", ["report_only_api"]));
+    let client = Client::tracked(rocket.configure(figment)).unwrap();
+    let post = |uri: &'static str| {
+        client
+            .post(uri)
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .header(Header::new("Origin", "https://evil.example"))
+            .dispatch()
+    };
+    let response = post("/report");
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_string().unwrap(),
+        "POST report_only_api https://evil.example cross_site_request true"
+    );
+    assert_eq!(post("/api").status(), Status::Forbidden);
+    assert_eq!(violation_counts(&client), (1, 1));
+}
+
+/// An event, along with the route it was for.
+type RecordedEvent = (CsrfEventKind, Option<String>);
+
+/// Records the events it receives.
+#[derive(Clone, Default)]
+struct RecordingListener(Arc<Mutex<Vec<RecordedEvent>>>);
+
+impl RecordingListener {
+    fn take(&self) -> Vec<RecordedEvent> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl CsrfEventListener for RecordingListener {
+    fn on_event(&self, event: &CsrfEvent<'_>) {
+        let route = event.route.map(str::to_owned);
+        self.0.lock().unwrap().push((event.kind, route));
+    }
+}
+
+#[test]
+fn test_listeners_receive_events() {
+    let listener = RecordingListener::default();
+    let client = logged_in_client!(build_rocket().attach(listener.clone().fairing()));
+    let route = |name: &str| Some(name.to_owned());
+    assert_eq!(
+        listener.take(),
+        [
+            (
+                CsrfEventKind::TokenIssued {
+                    verifier: "DoubleSubmitCookieCsrfToken"
+                },
+                route("show_login_page")
+            ),
+            (
+                CsrfEventKind::Verified {
+                    mechanism: CsrfMechanism::FormField
+                },
+                route("do_login")
+            ),
+        ]
+    );
+
+    let token = client.get("/one-time").dispatch().into_string().unwrap();
+    for _ in 0..2 {
+        client
+            .post("/one-time")
+            .header(Header::new("X-Csrf-Token", token.clone()))
+            .dispatch();
+    }
+    assert_eq!(
+        listener.take(),
+        [
+            (
+                CsrfEventKind::TokenIssued {
+                    verifier: "OneTimeCsrfToken"
+                },
+                route("issue_one_time_csrf_token")
+            ),
+            (
+                CsrfEventKind::Verified {
+                    mechanism: CsrfMechanism::Header
+                },
+                route("check_one_time_csrf_header")
+            ),
+            (
+                CsrfEventKind::Failed {
+                    mechanism: CsrfMechanism::Header,
+                    reason: CsrfViolationReason::TokenAlreadyUsed,
+                    report_only: false
+                },
+                route("check_one_time_csrf_header")
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_metrics_count_events() {
+    let client = report_only_client("csrf.report_only_routes", ["global_put"]);
+    let token = client.get("/token").dispatch().into_string().unwrap();
+    client.put("/").dispatch();
+    client.post("/").dispatch();
+    client
+        .post("/")
+        .header(Header::new("X-CSRF-Token", token))
+        .dispatch();
+
+    let metrics = client.rocket().state::<CsrfMetrics>().unwrap();
+    assert_eq!(metrics.tokens_issued(), 1);
+    assert_eq!(metrics.verified(CsrfMechanism::Header), 1);
+    assert_eq!(metrics.failures(CsrfViolationReason::MissingToken), 2);
+    assert_eq!(violation_counts(&client), (1, 1));
+    let prometheus = metrics.to_prometheus();
+    for line in [
+        "# TYPE csrf_tokens_issued_total counter",
+        "csrf_tokens_issued_total 1",
+        "csrf_verified_total{mechanism=\"header\"} 1",
+        "csrf_verified_total{mechanism=\"form_field\"} 0",
+        "csrf_failures_total{reason=\"missing_token\"} 2",
+        "# TYPE csrf_failures_reported_total counter",
+        "csrf_failures_reported_total 1",
+        "csrf_failures_enforced_total 1",
+    ] {
+        assert!(prometheus.lines().any(|l| l == line), "{prometheus}");
+    }
+}
+
+/// A form whose token a script copied out of a header, which it says it was checked with.
+#[derive(FromForm)]
+struct HeaderSourcedForm {
+    csrf_token: String,
+}
+
+impl WithUserProvidedCsrfToken for HeaderSourcedForm {
+    fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+
+    fn csrf_mechanism(&self) -> CsrfMechanism {
+        CsrfMechanism::Header
+    }
+}
+
+impl CsrfResubmittableForm for HeaderSourcedForm {
+    fn clear_secret_fields(&mut self) {}
+}
+
+#[post("/protected", data = "<_form>")]
+fn header_sourced_protected_form(
+    _form: CsrfProtectedForm<DoubleSubmitCookieCsrfToken, Form<HeaderSourcedForm>>,
+) -> &'static str {
+    "ok"
+}
+
+#[post("/recoverable", data = "<form>")]
+fn header_sourced_recoverable_form(form: CsrfRecoverableForm<Form<HeaderSourcedForm>>) -> Status {
+    match form {
+        CsrfRecoverableForm::Verified(_) => Status::Ok,
+        CsrfRecoverableForm::Resubmit(_) => Status::Forbidden,
+    }
+}
+
+#[test]
+fn test_forms_report_the_mechanism_of_their_token() {
+    let client = Client::tracked(
+        rocket::build()
+            .mount(
+                "/",
+                routes![
+                    global_token,
+                    header_sourced_protected_form,
+                    header_sourced_recoverable_form
+                ],
+            )
+            .attach(CsrfConfig::fairing()),
+    )
+    .unwrap();
+    for uri in ["/protected", "/recoverable"] {
+        let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+        let response = client
+            .post(uri)
+            .header(ContentType::Form)
+            .body(format!("csrf_token={csrf_token}"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+    let metrics = client.rocket().state::<CsrfMetrics>().unwrap();
+    assert_eq!(metrics.verified(CsrfMechanism::Header), 2);
+    assert_eq!(metrics.verified(CsrfMechanism::FormField), 0);
+}
+
+fn problem_reason(response: LocalResponse<'_>) -> String {
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(
+        response.content_type(),
+        Some(ContentType::new("application", "problem+json"))
+    );
+    let problem: Value = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(problem["status"], 403);
+    problem["reason"].as_str().unwrap().to_owned()
+}
+
+#[test]
+fn test_catcher_explains_csrf_failures() {
+    let client = logged_in_client!();
+    let response = client
+        .get("/header")
+        .header(Header::new("X-Csrf-Token", "wrong_token"))
+        .header(Header::new("Accept", "application/problem+json"))
+        .dispatch();
+    assert_eq!(problem_reason(response), "invalid_token");
+
+    let response = client.get("/header").dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(response.content_type(), Some(ContentType::HTML));
+    assert!(response
+        .into_string()
+        .unwrap()
+        .contains("<code>missing_token</code>"));
+}
+
+#[post("/result")]
+fn header_check_result(
+    check: Result<
+        CheckCsrfProtectionHeader<DoubleSubmitCookieCsrfToken>,
+        CheckCsrfProtectionHeaderError,
+    >,
+) -> Result<&'static str, CheckCsrfProtectionHeaderError> {
+    check.map(|_| "ok")
+}
+
+#[test]
+fn test_guard_errors_respond_with_reason() {
+    let rocket = rocket::build().mount("/", routes![global_token, header_check_result]);
+    let client = Client::tracked(rocket).unwrap();
+    client.get("/token").dispatch();
+    let response = client
+        .post("/result")
+        .header(Header::new("Accept", "application/json"))
+        .dispatch();
+    assert_eq!(problem_reason(response), "missing_token");
+
+    client.get("/token").dispatch();
+    let response = client
+        .post("/result")
+        .header(Header::new("X-CSRF-Token", "wrong_token"))
+        .header(Header::new("Accept", "application/json"))
+        .dispatch();
+    assert_eq!(problem_reason(response), "invalid_token");
+
+    let csrf_token = client.get("/token").dispatch().into_string().unwrap();
+    let response = client
+        .post("/result")
+        .header(Header::new("X-CSRF-Token", csrf_token))
+        .dispatch();
+    assert_eq!(response.into_string().unwrap(), "ok");
+}
+
+#[derive(FromForm)]
+struct Upload<'r> {
+    file: TempFile<'r>,
+}
+
+/// How many times the [`Counted`] forms have been parsed, one counter per test.
+static PARSES: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+/// A form which counts how often it is parsed in `PARSES[N]`.
+struct Counted<F, const N: usize>(F);
+
+#[rocket::async_trait]
+impl<'r, F, const N: usize> FromData<'r> for Counted<F, N>
+where
+    F: FromData<'r>,
+{
+    type Error = F::Error;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        PARSES[N].fetch_add(1, Ordering::SeqCst);
+        F::from_data(request, data).await.map(Self)
+    }
+}
+
+impl<F, const N: usize> WithUserProvidedCsrfToken for Counted<F, N>
+where
+    F: WithUserProvidedCsrfToken,
+{
+    fn csrf_token(&self) -> &str {
+        self.0.csrf_token()
+    }
+}
+
+type CountedUpload<'r> = Counted<Form<Upload<'r>>, 0>;
+
+#[post("/upload", data = "<form>")]
+fn upload(
+    form: CsrfProtectedMultipartForm<DoubleSubmitCookieCsrfToken, CountedUpload<'_>>,
+) -> String {
+    form.into_inner().0.file.len().to_string()
+}
+
+fn multipart_body(parts: &[(&str, &str)]) -> String {
+    let mut body = String::new();
+    for (name, value) in parts {
+        let disposition = if *name == "file" {
+            r#"form-data; name="file"; filename="upload.txt""#.to_owned()
+        } else {
+            format!(r#"form-data; name="{name}""#)
+        };
+        body.push_str(&format!(
+            "--BOUNDARY\r\nContent-Disposition: {disposition}\r\n\r\n{value}\r\n"
+        ));
+    }
+    body.push_str("--BOUNDARY--\r\n");
+    body
+}
+
+#[test]
+fn test_multipart_form_is_verified_before_parsing() {
+    let client =
+        Client::tracked(rocket::build().mount("/", routes![global_token, upload])).unwrap();
+    let content_type =
+        ContentType::new("multipart", "form-data").with_params(("boundary", "BOUNDARY"));
+    let post = |parts: &[(&str, &str)]| {
+        client
+            .post("/upload")
+            .header(content_type.clone())
+            .body(multipart_body(parts))
+            .dispatch()
+    };
+    let fresh_token = || client.get("/token").dispatch().into_string().unwrap();
+    let file = "x".repeat(4096);
+    let parses = PARSES[0].load(Ordering::SeqCst);
+
+    // Forged uploads never reach the form parser.
+    fresh_token();
+    let response = post(&[("csrf_token", "wrong"), ("file", &file)]);
+    assert_eq!(response.status(), Status::Forbidden);
+    // A valid token after the file is not looked at, since only the first part is read.
+    let token = fresh_token();
+    let response = post(&[("file", &file), ("csrf_token", &token)]);
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(PARSES[0].load(Ordering::SeqCst), parses);
+
+    let token = fresh_token();
+    let response = post(&[("csrf_token", &token), ("file", &file)]);
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "4096");
+    assert_eq!(PARSES[0].load(Ordering::SeqCst), parses + 1);
+}
+
+#[derive(FromForm, rocket::serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Comment {
+    text: String,
+}
+
+type CountedComment = Counted<Form<Comment>, 1>;
+
+#[post("/comment", data = "<form>")]
+fn comment(form: CsrfPreverifiedForm<DoubleSubmitCookieCsrfToken, CountedComment>) -> String {
+    form.into_inner().0.into_inner().text
+}
+
+#[test]
+fn test_preverified_form_rejects_forgeries_before_parsing() {
+    let client =
+        Client::tracked(rocket::build().mount("/", routes![global_token, comment])).unwrap();
+    let fresh_token = || client.get("/token").dispatch().into_string().unwrap();
+    let post = |body: String| client.post("/comment").header(ContentType::Form).body(body);
+    let text = "y".repeat(4096);
+    let parses = PARSES[1].load(Ordering::SeqCst);
+
+    fresh_token();
+    let response = post(format!("csrf_token=wrong&text={text}")).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    // Tokens past the start of the body are not looked at.
+    let token = fresh_token();
+    let response = post(format!("text={text}&csrf_token={token}")).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(PARSES[1].load(Ordering::SeqCst), parses);
+
+    // The token can come first in the body...
+    let token = fresh_token();
+    let response = post(format!("csrf_token={token}&text=hello")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().unwrap(), "hello");
+
+    // ... or from the header, in which case the body need not contain it at all.
+    let token = fresh_token();
+    let response = post(format!("text={text}"))
+        .header(Header::new("X-CSRF-Token", token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(PARSES[1].load(Ordering::SeqCst), parses + 2);
+}
+
+#[derive(FromForm)]
+struct Note {
+    csrf_token: String,
+    text: String,
+}
+
+impl WithUserProvidedCsrfToken for Note {
+    fn csrf_token(&self) -> &str {
+        &self.csrf_token
+    }
+}
+
+type CountedNote = Counted<Form<Note>, 2>;
+
+/// A verifier which can never be loaded, e.g. because the session store is down.
+struct UnavailableVerifier;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UnavailableVerifier {
+    type Error = ();
+
+    async fn from_request(_request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Error((Status::ServiceUnavailable, ()))
+    }
+}
+
+impl VerifierWithKnownExpectedToken for UnavailableVerifier {
+    type Proof = CsrfCheckProof;
+
+    fn expected_token(&self) -> &str {
+        unreachable!("the verifier is never loaded")
+    }
+}
+
+#[post("/note", data = "<form>")]
+fn note(form: CsrfProtectedForm<UnavailableVerifier, CountedNote>) -> String {
+    form.into_inner().0.into_inner().text
+}
+
+#[post("/guarded-note", data = "<_form>")]
+fn guarded_note(
+    _form: CsrfProtectedFormWithGuard<'_, UnavailableVerifier, CountedNote, CsrfCheckProof>,
+) -> &'static str {
+    "ok"
+}
+
+#[test]
+fn test_protected_form_without_verifier_is_rejected_before_parsing() {
+    let build_client = |report_only_routes: &[&str]| {
+        let rocket = rocket::build()
+            .mount("/", routes![global_token, note, guarded_note])
+            .attach(CsrfConfig::fairing());
+        let figment = rocket
+            .figment()
+            .clone()
+            .merge(("csrf.report_only_routes", report_only_routes));
+        Client::tracked(rocket.configure(figment)).unwrap()
+    };
+    let post = |client: &Client, uri: &'static str, body: &str| {
+        client
+            .post(uri)
+            .header(ContentType::Form)
+            .body(body)
+            .dispatch()
+            .status()
+    };
+
+    // Without a verifier, the body is not parsed, even if it is malformed.
+    let client = build_client(&[]);
+    let parses = PARSES[2].load(Ordering::SeqCst);
+    for uri in ["/note", "/guarded-note"] {
+        assert_eq!(
+            post(&client, uri, "csrf_token=x&text=hi"),
+            Status::ServiceUnavailable
+        );
+        assert_eq!(
+            post(&client, uri, "csrf_token=x"),
+            Status::ServiceUnavailable
+        );
+    }
+    assert_eq!(PARSES[2].load(Ordering::SeqCst), parses);
+    assert_eq!(violation_counts(&client), (0, 4));
+
+    // Unless failures are only reported, in which case the form is let through.
+    let client = build_client(&["note"]);
+    assert_eq!(post(&client, "/note", "csrf_token=x&text=hi"), Status::Ok);
+    assert_eq!(PARSES[2].load(Ordering::SeqCst), parses + 1);
+    assert_eq!(violation_counts(&client), (1, 0));
+}
+
 // Poor man's macrotest, since that doesn't work with our workspace setup.
 fn verify_expansion_case(name: &str) {
     println!("Running expansion test case {name}...");
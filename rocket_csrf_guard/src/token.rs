@@ -1,10 +1,18 @@
+use crate::event::CsrfMechanism;
+
 use std::ops::Deref;
 
-use rocket::form::Form;
+use rocket::{form::Form, serde::json::Json};
 
 /// A thing that has a csrf token provided from user input
 pub trait WithUserProvidedCsrfToken {
     fn csrf_token(&self) -> &str;
+
+    /// Where the token was found, for [`crate::CsrfCheckProof::mechanism`].
+    /// Defaults to a field of the body, as in forms.
+    fn csrf_mechanism(&self) -> CsrfMechanism {
+        CsrfMechanism::FormField
+    }
 }
 
 /// Convenience implementation for [`rocket::form::Form`] which
@@ -16,6 +24,26 @@ where
     fn csrf_token(&self) -> &str {
         self.deref().csrf_token()
     }
+
+    fn csrf_mechanism(&self) -> CsrfMechanism {
+        self.deref().csrf_mechanism()
+    }
+}
+
+/// Convenience implementation for [`rocket::serde::json::Json`] which
+/// automatically provides a csrf token if the inner type does.
+/// [`crate::with_csrf_token`] works on `Deserialize` types too, as long as it comes first.
+impl<T> WithUserProvidedCsrfToken for Json<T>
+where
+    T: WithUserProvidedCsrfToken,
+{
+    fn csrf_token(&self) -> &str {
+        self.deref().csrf_token()
+    }
+
+    fn csrf_mechanism(&self) -> CsrfMechanism {
+        self.deref().csrf_mechanism()
+    }
 }
 
 /// Construct a CsrfToken from thin air.
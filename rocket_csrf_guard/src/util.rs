@@ -12,3 +12,65 @@ pub(crate) fn random_id(len: usize) -> Result<String, rand::Error> {
     rand::thread_rng().try_fill_bytes(&mut buf)?;
     Ok(base64::encode_config(buf, base64::URL_SAFE_NO_PAD))
 }
+
+/// The current time, in seconds since the unix epoch.
+pub(crate) fn now_unix_seconds() -> i64 {
+    rocket::time::OffsetDateTime::now_utc().unix_timestamp()
+}
+
+/// Whether something issued at `issued_at` is older than `max_age` (both in seconds).
+pub(crate) fn is_expired(issued_at: i64, max_age: i64) -> bool {
+    now_unix_seconds().saturating_sub(issued_at) > max_age
+}
+
+/// Compares two byte strings in constant time.
+///
+/// The running time depends only on the lengths of the inputs, never on where they differ,
+/// so comparing a user provided token against the expected one does not leak how long the
+/// matching prefix is. Inputs of different lengths are never equal.
+#[inline(never)]
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    // Keep the optimizer from short circuiting the fold above.
+    std::hint::black_box(difference) == 0
+}
+
+/// Masks a token with a fresh one-time pad, returning `base64(mask || token ^ mask)`.
+///
+/// Every call returns a different encoding of the same token, so rendering it into a
+/// compressed response does not let attackers recover it through compression side
+/// channels such as [BREACH](https://en.wikipedia.org/wiki/BREACH).
+pub(crate) fn mask_token(token: &str) -> Result<String, rand::Error> {
+    let token = token.as_bytes();
+    let mut buf = vec![0; token.len() * 2];
+    let (mask, masked) = buf.split_at_mut(token.len());
+    rand::thread_rng().try_fill_bytes(mask)?;
+    for ((out, t), m) in masked.iter_mut().zip(token).zip(mask.iter()) {
+        *out = t ^ m;
+    }
+    Ok(base64::encode_config(buf, base64::URL_SAFE_NO_PAD))
+}
+
+/// Reverses [`mask_token`], returning `None` if the input is not a masked token.
+pub(crate) fn unmask_token(masked: &str) -> Option<Vec<u8>> {
+    let buf = base64::decode_config(masked, base64::URL_SAFE_NO_PAD).ok()?;
+    if buf.is_empty() || buf.len() % 2 != 0 {
+        return None;
+    }
+    let (mask, masked) = buf.split_at(buf.len() / 2);
+    Some(masked.iter().zip(mask).map(|(t, m)| t ^ m).collect())
+}
+
+/// Checks whether a user provided token matches the expected one.
+///
+/// Accepts both masked (see [`mask_token`]) and raw tokens, so callers which pass the
+/// token along verbatim keep working. All comparisons are done in constant time.
+pub(crate) fn token_matches(provided: &str, expected: &str) -> bool {
+    let unmasked_matches = unmask_token(provided)
+        .is_some_and(|unmasked| constant_time_eq(&unmasked, expected.as_bytes()));
+    let raw_matches = constant_time_eq(provided.as_bytes(), expected.as_bytes());
+    unmasked_matches | raw_matches
+}
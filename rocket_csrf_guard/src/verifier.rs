@@ -1,5 +1,12 @@
-use crate::token::WithUserProvidedCsrfToken;
+use crate::{
+    proof::CsrfCheckProof,
+    report::CsrfViolationReason,
+    scoped::{action_scoped_token, csrf_action_scope},
+    token::WithUserProvidedCsrfToken,
+    util::{is_expired, mask_token, token_matches},
+};
 use anyhow::Result;
+use rocket::http::Method;
 
 /// A type that can verify whether a [`WithUserProvidedCsrfToken`] actually has a valid csrf token
 /// Lets us be generic over session based or other csrf tokens
@@ -15,13 +22,76 @@ pub trait CsrfTokenVerifier {
         &self,
         token: &(dyn WithUserProvidedCsrfToken + Send + Sync),
     ) -> Result<Self::Proof, Self::Error>;
+
+    /// Why verification failed, for [`crate::CsrfViolation`]s.
+    fn violation_reason(_error: &Self::Error) -> CsrfViolationReason {
+        CsrfViolationReason::InvalidToken
+    }
+
+    /// The proof handed out when a failed check is let through in report-only mode, see
+    /// [`crate::CsrfConfig::report_only`]. Verifiers which return `None` (the default) are
+    /// always enforced.
+    ///
+    /// Only this crate can make, or even name, the argument, so this cannot be used to make
+    /// up proofs elsewhere, and only this crate's verifiers can be let through.
+    fn report_only_proof(_check: sealed::ReportOnlyCheck) -> Option<Self::Proof> {
+        None
+    }
+}
+
+pub(crate) mod sealed {
+    use crate::event::CsrfMechanism;
+
+    /// A failed check which is only reported, see [`super::CsrfTokenVerifier::report_only_proof`].
+    pub struct ReportOnlyCheck {
+        /// How the request was checked.
+        pub(crate) mechanism: CsrfMechanism,
+    }
 }
 
 /// Trait for easily implementing a verifier when you know the expected token.
+///
+/// The proof is made from the [`CsrfCheckProof`] handed out when the check passes, which
+/// cannot be made any other way, so use that or a type which wraps it.
 pub trait VerifierWithKnownExpectedToken {
-    type Proof: Default + Send + Sync + 'static;
+    type Proof: From<CsrfCheckProof> + Send + Sync + 'static;
 
     fn expected_token(&self) -> &str;
+
+    /// A token which [`Self::expected_token`] replaced, but which is still accepted,
+    /// e.g. during the grace window after [`crate::RotatingCsrfToken::rotate`].
+    fn previous_expected_token(&self) -> Option<&str> {
+        None
+    }
+
+    /// When the expected token was issued, in seconds since the unix epoch.
+    /// Tokens are only checked for expiry if this and [`Self::max_age_seconds`] are provided.
+    fn issued_at(&self) -> Option<i64> {
+        None
+    }
+
+    /// How long the expected token is valid for after being issued, in seconds.
+    fn max_age_seconds(&self) -> Option<i64> {
+        None
+    }
+
+    /// Returns a freshly masked copy of the expected token.
+    /// Use this when rendering the token into a response, so the same bytes are never
+    /// sent twice and compression side channels cannot be used to recover it.
+    fn masked_expected_token(&self) -> Result<String, rand::Error> {
+        mask_token(self.expected_token())
+    }
+
+    /// Returns a freshly masked token which only works for the given action, when checked
+    /// by [`crate::ActionScopedCsrfToken`]. See [`crate::csrf_action_scope`] for the arguments.
+    fn masked_action_scoped_token(
+        &self,
+        method: Method,
+        route_uri: &str,
+    ) -> Result<String, rand::Error> {
+        let scope = csrf_action_scope(method, route_uri);
+        mask_token(&action_scoped_token(self.expected_token(), &scope))
+    }
 }
 
 /// Errors which can happen when verifying a CSRF token
@@ -32,6 +102,16 @@ pub enum CsrfTokenVerificationError {
     /// to avoid bugs where the token gets returned to users.
     #[error("CSRF token did not match!")]
     CsrfTokenMismatch,
+    /// The CSRF token matched, but it is older than its maximum age.
+    #[error("CSRF token has expired!")]
+    Expired,
+    /// The CSRF token was valid, but has already been used and cannot be used again.
+    #[error("CSRF token has already been used!")]
+    AlreadyUsed,
+    /// The CSRF token was signed with a key which has since been retired,
+    /// see [`crate::CsrfKeyring`].
+    #[error("CSRF token was signed with a retired key!")]
+    RetiredKey,
     /// For extensibility
     #[error("Unknown error: {0:?}")]
     Unknown(Box<dyn std::error::Error + Send + Sync>),
@@ -41,7 +121,7 @@ pub enum CsrfTokenVerificationError {
 #[async_trait::async_trait]
 impl<Proof, T> CsrfTokenVerifier for T
 where
-    Proof: Default + Send + Sync + 'static,
+    Proof: From<CsrfCheckProof> + Send + Sync + 'static,
     T: VerifierWithKnownExpectedToken<Proof = Proof> + Send + Sync + 'static,
 {
     type Proof = Proof;
@@ -51,10 +131,29 @@ where
         &self,
         token: &(dyn WithUserProvidedCsrfToken + Send + Sync),
     ) -> Result<Self::Proof, Self::Error> {
-        if token.csrf_token() == self.expected_token() {
-            Ok(Self::Proof::default())
-        } else {
-            Err(CsrfTokenVerificationError::CsrfTokenMismatch)
+        if !token_matches(token.csrf_token(), self.expected_token()) {
+            // A rotated out token is only accepted until its grace window ends, regardless
+            // of when the new token was issued.
+            return match self.previous_expected_token() {
+                Some(previous) if token_matches(token.csrf_token(), previous) => {
+                    Ok(CsrfCheckProof::new::<Self>(token.csrf_mechanism()).into())
+                }
+                _ => Err(CsrfTokenVerificationError::CsrfTokenMismatch),
+            };
+        }
+        match (self.issued_at(), self.max_age_seconds()) {
+            (Some(issued_at), Some(max_age)) if is_expired(issued_at, max_age) => {
+                Err(CsrfTokenVerificationError::Expired)
+            }
+            _ => Ok(CsrfCheckProof::new::<Self>(token.csrf_mechanism()).into()),
         }
     }
+
+    fn violation_reason(error: &Self::Error) -> CsrfViolationReason {
+        error.into()
+    }
+
+    fn report_only_proof(check: sealed::ReportOnlyCheck) -> Option<Self::Proof> {
+        Some(CsrfCheckProof::excused::<Self>(check.mechanism).into())
+    }
 }
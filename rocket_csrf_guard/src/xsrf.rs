@@ -0,0 +1,178 @@
+use crate::{
+    config::CsrfConfig,
+    event::{emit, CsrfEventKind, CsrfMechanism},
+    keyring::CsrfKeyring,
+    report::CsrfViolationReason,
+    util::{random_id, token_matches},
+    verifier::sealed::ReportOnlyCheck,
+    CsrfCheckProof, CsrfTokenVerificationError, CsrfTokenVerifier, WithUserProvidedCsrfToken,
+};
+
+use rocket::{
+    http::{Cookie, CookieJar, SameSite, Status},
+    request::{FromRequest, Outcome, Request},
+    State,
+};
+
+/// Default name of the cookie set by [`SetXsrfCookieCsrfToken`], which is what Angular and
+/// Axios look for.
+pub const XSRF_COOKIE_NAME: &str = "XSRF-TOKEN";
+
+/// Provides a verifier for the "cookie-to-header" pattern used by single page apps.
+///
+/// [`SetXsrfCookieCsrfToken`] sets a cookie (`XSRF-TOKEN` by default) which, unlike the
+/// double submit cookie, is neither encrypted nor `HttpOnly`, so scripts can read it and
+/// echo it back in the configured header. Check the header with
+/// [`crate::CheckCsrfProtectionHeader`] using this verifier. For Angular, set
+/// [`CsrfConfig::header_name`] to `X-XSRF-TOKEN`.
+///
+/// Since the cookie is readable, tokens are signed with the [`CsrfKeyring`] instead, so only
+/// tokens minted by the server are accepted, and their age cannot be tampered with. Tokens
+/// expire after [`CsrfConfig::expiry_seconds`]. They are not bound to a session, so prefer
+/// [`crate::SignedCsrfToken`] once the user is logged in.
+///
+/// Because of that, and because the cookie cannot have a `__Host-` prefix, an attacker
+/// who controls a sibling subdomain can plant a token they got from the site. What stops
+/// them is that cross site requests cannot set custom headers, so only tokens from the
+/// header ([`CsrfMechanism::Header`]) are accepted; tokens from a form or JSON body, such as
+/// those a [`crate::CsrfFairing`] reads, are always rejected.
+pub struct XsrfCookieCsrfToken {
+    cookie: String,
+    keyring: CsrfKeyring,
+    max_age: i64,
+}
+
+impl XsrfCookieCsrfToken {
+    /// Verifies a token like [`CsrfTokenVerifier::verify`], returning the ID of the key in
+    /// the [`CsrfKeyring`] which signed the cookie.
+    /// This does not know where the token came from, so only call it with header values.
+    pub fn verify_key_id(&self, token: &str) -> Result<&str, CsrfTokenVerificationError> {
+        if !token_matches(token, &self.cookie) {
+            return Err(CsrfTokenVerificationError::CsrfTokenMismatch);
+        }
+        self.keyring
+            .verify(&self.cookie, self.max_age, |key, issued_at, nonce| {
+                key.sign_unbound(issued_at, nonce)
+            })
+    }
+}
+
+impl std::fmt::Debug for XsrfCookieCsrfToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("XsrfCookieCsrfToken")
+            .field("keyring", &self.keyring)
+            .field("max_age", &self.max_age)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Verifies that the received token matches the cookie, and that the cookie was signed by us.
+#[async_trait::async_trait]
+impl CsrfTokenVerifier for XsrfCookieCsrfToken {
+    type Proof = CsrfCheckProof;
+    type Error = CsrfTokenVerificationError;
+
+    async fn verify(
+        &self,
+        token: &(dyn WithUserProvidedCsrfToken + Send + Sync),
+    ) -> Result<Self::Proof, Self::Error> {
+        if token.csrf_mechanism() != CsrfMechanism::Header {
+            return Err(CsrfTokenVerificationError::CsrfTokenMismatch);
+        }
+        let key_id = self.verify_key_id(token.csrf_token())?;
+        log::debug!("XSRF cookie was signed with key `{key_id}`");
+        Ok(CsrfCheckProof::new::<Self>(token.csrf_mechanism()))
+    }
+
+    fn violation_reason(error: &Self::Error) -> CsrfViolationReason {
+        error.into()
+    }
+
+    fn report_only_proof(check: ReportOnlyCheck) -> Option<Self::Proof> {
+        Some(CsrfCheckProof::excused::<Self>(check.mechanism))
+    }
+}
+
+/// Extracts the cookie and the signing keys. The cookie is left in place, so every open tab
+/// and in flight request can keep using it.
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for XsrfCookieCsrfToken {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = CsrfConfig::from_request(request);
+        let Some(cookie) = request.cookies().get(&config.xsrf_cookie_name) else {
+            return Outcome::Forward(Status::BadRequest);
+        };
+        let Some(keyring) = request.guard::<&State<CsrfKeyring>>().await.succeeded() else {
+            return Outcome::Forward(Status::InternalServerError);
+        };
+        Outcome::Success(Self {
+            cookie: cookie.value().to_owned(),
+            keyring: keyring.inner().clone(),
+            max_age: config.expiry_seconds,
+        })
+    }
+}
+
+/// A helper to set the cookie checked by [`XsrfCookieCsrfToken`].
+///
+/// Tokens are of the form `key_id.issued_at.nonce.signature`, see [`CsrfKeyring`].
+pub struct SetXsrfCookieCsrfToken<'r> {
+    cookies: &'r CookieJar<'r>,
+    cookie_name: &'r str,
+    expiry_seconds: i64,
+    csrf_token: String,
+}
+
+impl<'r> SetXsrfCookieCsrfToken<'r> {
+    /// Sets the cookie, returning the token in it.
+    pub fn set(&self) -> &str {
+        let cookie = Cookie::build((self.cookie_name.to_owned(), self.csrf_token.clone()))
+            .path("/")
+            .max_age(rocket::time::Duration::seconds(self.expiry_seconds))
+            .same_site(SameSite::Strict)
+            .secure(true)
+            .http_only(false);
+        self.cookies.add(cookie);
+        &self.csrf_token
+    }
+}
+
+impl std::fmt::Debug for SetXsrfCookieCsrfToken<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SetXsrfCookieCsrfToken")
+            .field("cookie_name", &self.cookie_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Mints a signed token which can be set as a cookie.
+#[async_trait::async_trait]
+impl<'r> FromRequest<'r> for SetXsrfCookieCsrfToken<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = CsrfConfig::from_request(request);
+        let Some(keyring) = request.guard::<&State<CsrfKeyring>>().await.succeeded() else {
+            return Outcome::Forward(Status::InternalServerError);
+        };
+        let Ok(nonce) = random_id(config.token_length) else {
+            return Outcome::Forward(Status::InternalServerError);
+        };
+        emit(
+            request,
+            CsrfEventKind::TokenIssued {
+                verifier: "XsrfCookieCsrfToken",
+            },
+        );
+        Outcome::Success(Self {
+            cookies: request.cookies(),
+            cookie_name: &config.xsrf_cookie_name,
+            expiry_seconds: config.expiry_seconds,
+            csrf_token: keyring.issue(&nonce, |key, issued_at, nonce| {
+                key.sign_unbound(issued_at, nonce)
+            }),
+        })
+    }
+}
@@ -61,7 +61,7 @@ pub fn with_csrf_token(args: TokenStream, input: TokenStream) -> TokenStream {
         let existing = fields
             .named
             .iter()
-            .any(|f| f.ident.as_ref().map_or(false, |i| *i == ident));
+            .any(|f| f.ident.as_ref().is_some_and(|i| *i == ident));
         // TODO: Validate field type is string or &str
         if !existing {
             if let Some(lifetime) = lifetime {